@@ -0,0 +1,149 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+
+use image::Pixel;
+
+use crate::pixel::{alpha, Channel, RgbaBuffer};
+
+/// The ways a [`Tile`](crate::Tile) can be recolored toward the cell of the
+/// original image it was chosen for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorAdjustMode {
+    /// Shift every pixel of the tile so its mean matches the cell's mean.
+    MeanShift,
+    /// Scale each channel of the tile so its mean matches the cell's mean.
+    Gain,
+    /// Remap each channel of the tile so its histogram matches the cell's.
+    ///
+    /// This is most useful with a [`Grid`](crate::Grid) larger than `1×1`,
+    /// since a single-pixel cell has a single-value histogram.
+    HistogramMatch,
+    /// Lay the cell's mean color over the tile.
+    Overlay,
+}
+
+/// A color adjustment applied to each [`Tile`](crate::Tile) placed in a
+/// [`Mosaic`](crate::Mosaic).
+///
+/// This lets poorly matched tiles blend in, so small tile libraries can
+/// still produce accurate mosaics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorAdjust {
+    /// How to recolor the tile.
+    pub mode: ColorAdjustMode,
+    /// How strongly to apply the adjustment, from `0.0` (leave the tile
+    /// unchanged) to `1.0` (apply it fully).
+    pub strength: f32,
+}
+
+impl ColorAdjust {
+    /// Recolor a tile toward the given pixels of the cell it was chosen for.
+    ///
+    /// Pixels are weighted by their alpha, and the alpha of the tile is
+    /// left unchanged.
+    pub(crate) fn apply<'a, C: Channel>(&self, tile: &'a RgbaBuffer<C>, cell: &[C::Rgba]) -> Cow<'a, RgbaBuffer<C>> {
+        let strength = self.strength.clamp(0.0, 1.0);
+        if strength == 0.0 || cell.is_empty() {
+            return Cow::Borrowed(tile);
+        }
+
+        let tile_mean = mean::<C>(tile.pixels());
+        let cell_mean = mean::<C>(cell.iter());
+        let cdfs: Vec<_> = match self.mode {
+            ColorAdjustMode::HistogramMatch => (0..3)
+                .map(|c| {
+                    let src = cdf(tile.pixels().map(|p| (p.channels()[c], p.channels()[3])));
+                    let dst = cdf(cell.iter().map(|p| (p.channels()[c], p.channels()[3])));
+                    (src, dst)
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        // values are adjusted on a 0-255 scale whatever the channel type
+        let adjust = |c: usize, v: C| match self.mode {
+            ColorAdjustMode::MeanShift => scaled(v) + cell_mean[c] - tile_mean[c],
+            ColorAdjustMode::Gain => scaled(v) * cell_mean[c] / tile_mean[c].max(1.0),
+            ColorAdjustMode::HistogramMatch => {
+                let (src, dst) = &cdfs[c];
+                let matched = dst.partition_point(|&d| d < src[level(v)]).min(C::LEVELS - 1);
+                matched as f32 * 255.0 / (C::LEVELS - 1) as f32
+            }
+            ColorAdjustMode::Overlay => cell_mean[c],
+        };
+
+        let mut out = tile.clone();
+        for px in out.pixels_mut() {
+            for (c, v) in px.channels_mut().iter_mut().take(3).enumerate() {
+                // blend the fully adjusted value with the original
+                let full = scaled(unscaled::<C>(adjust(c, *v)));
+                let original = scaled(*v);
+                *v = unscaled(original + strength * (full - original));
+            }
+        }
+        Cow::Owned(out)
+    }
+}
+
+/// Convert a channel value to the range `[0, 255]`.
+fn scaled<C: Channel>(v: C) -> f32 {
+    (v.to_unit() * 255.0) as f32
+}
+
+/// Convert a value in the range `[0, 255]` to a channel value.
+fn unscaled<C: Channel>(v: f32) -> C {
+    C::from_unit(v as f64 / 255.0)
+}
+
+/// Get the histogram bin of a channel value.
+fn level<C: Channel>(v: C) -> usize {
+    let top = (C::LEVELS - 1) as f64;
+    (v.to_unit() * top).round().clamp(0.0, top) as usize
+}
+
+/// Compute the mean of each color channel of the given pixels on a `0-255`
+/// scale, weighted by their alpha.
+fn mean<'a, C: Channel>(pixels: impl Iterator<Item = &'a C::Rgba>) -> [f32; 3] {
+    let mut tot = [0f64; 3];
+    let mut weight = 0f64;
+    for px in pixels {
+        let alpha = alpha::<C>(px);
+        for (t, c) in tot.iter_mut().zip(px.channels()) {
+            *t += alpha * c.to_unit() * 255.0;
+        }
+        weight += alpha;
+    }
+    tot.map(|t| (t / weight.max(1.0 / 255.0)) as f32)
+}
+
+/// Compute the cumulative distribution of the given channel values over
+/// [`Channel::LEVELS`] bins, each value weighted by the alpha it comes with.
+fn cdf<C: Channel>(values: impl Iterator<Item = (C, C)>) -> Vec<f32> {
+    let mut hist = vec![0f64; C::LEVELS];
+    let mut total = 0.0;
+    for (v, alpha) in values {
+        hist[level(v)] += alpha.to_unit();
+        total += alpha.to_unit();
+    }
+
+    let mut acc = 0.0;
+    hist.into_iter()
+        .map(|h| {
+            acc += h;
+            (acc / f64::max(total, f64::MIN_POSITIVE)) as f32
+        })
+        .collect()
+}
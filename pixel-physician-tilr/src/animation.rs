@@ -0,0 +1,197 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, Delay, Frame, ImageFormat, RgbaImage};
+
+use crate::error::TilrError;
+use crate::mosaic::Mosaic;
+use crate::utils::load_image;
+
+/// A mosaic of each frame of an animation, such as an animated GIF or PNG.
+///
+/// Build a [`Mosaic`] for each frame, all from the same [`TileSet`](crate::TileSet)
+/// shared through an [`Arc`](std::sync::Arc), e.g. with a
+/// [`MosaicBuilder`](crate::MosaicBuilder) per frame of [`load_frames`]:
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use std::sync::Arc;
+/// # use pixel_physician_tilr::{load_frames, AnimatedMosaic, MosaicBuilder, TileSet, TilrError};
+/// # fn build(tiles: TileSet) -> Result<(), Box<dyn std::error::Error>> {
+/// let tiles = Arc::new(tiles);
+/// let frames = load_frames(Path::new("cat.gif"))?
+///     .into_iter()
+///     .map(|frame| {
+///         let delay = frame.delay();
+///         let mosaic = MosaicBuilder::new(frame.into_buffer(), Arc::clone(&tiles)).build()?;
+///         Ok((mosaic, delay))
+///     })
+///     .collect::<Result<_, TilrError>>()?;
+/// let animation = AnimatedMosaic::new(frames)?.with_coherence(10.0);
+/// animation.write_gif(std::fs::File::create("cat-mosaic.gif")?)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Matching each frame on its own makes cells flicker between similar tiles
+/// from one frame to the next; see [`AnimatedMosaic::with_coherence`].
+#[allow(missing_debug_implementations)]
+pub struct AnimatedMosaic {
+    /// The mosaic of each frame, and how long the frame is shown.
+    frames: Vec<(Mosaic, Delay)>,
+    /// How far the colors of a cell may be from the tile it had in the
+    /// previous frame for the tile to be kept, if at all.
+    coherence: Option<f32>,
+}
+
+impl AnimatedMosaic {
+    /// Animate the given mosaics, each shown for the given delay.
+    ///
+    /// # Errors
+    /// Fails if there are no frames, or if the mosaics aren't all the same
+    /// size.
+    pub fn new(frames: Vec<(Mosaic, Delay)>) -> Result<Self, TilrError> {
+        let (first, _) = frames.first().ok_or(TilrError::ZeroSize("animation"))?;
+        let size = first.output_size();
+        if let Some(frame) = frames.iter().position(|(mosaic, _)| mosaic.output_size() != size) {
+            return Err(TilrError::FrameSizeMismatch { frame });
+        }
+        Ok(Self { frames, coherence: None })
+    }
+
+    /// Keep the tile of each cell from one frame to the next, as long as it
+    /// is within `threshold` of the colors of the cell, in the units of the
+    /// [`ColorMetric`](crate::ColorMetric)'s color space.
+    ///
+    /// This only applies between frames that share a
+    /// [`TileSet`](crate::TileSet), to cells of the same size and position.
+    /// Kept tiles don't count toward [`Selection::max_uses`](crate::Selection::max_uses)
+    /// or [`Selection::repeat_radius`](crate::Selection::repeat_radius).
+    pub fn with_coherence(mut self, threshold: f32) -> Self {
+        self.coherence = Some(threshold);
+        self
+    }
+
+    /// Get the number of frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Check whether there are no frames; never true for an animation built
+    /// with [`AnimatedMosaic::new`].
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Get the width and height of each frame in pixels.
+    pub fn output_size(&self) -> (u32, u32) {
+        self.frames[0].0.output_size()
+    }
+
+    /// Generate the mosaic of each frame in order, passing each one to the
+    /// given function along with its delay.
+    ///
+    /// # Errors
+    /// Fails if the function fails, or if a frame is cancelled.
+    pub fn render_frames(
+        self,
+        mut each: impl FnMut(RgbaImage, Delay) -> Result<(), TilrError>,
+    ) -> Result<(), TilrError> {
+        let shared: Vec<bool> = self.frames.windows(2).map(|w| w[1].0.shares_tiles(&w[0].0)).collect();
+        let mut last = None;
+        for (i, (mut mosaic, delay)) in self.frames.into_iter().enumerate() {
+            if let (Some(threshold), Some(last)) = (self.coherence, last.take()) {
+                if shared[i - 1] {
+                    mosaic = mosaic.with_previous(last, threshold);
+                }
+            }
+            // only the choices are needed for the next frame
+            last = self.coherence.map(|_| mosaic.chosen()).transpose()?;
+            each(mosaic.into_rgba_image()?, delay)?;
+        }
+        Ok(())
+    }
+
+    /// Write the animation as a GIF that loops forever.
+    ///
+    /// GIFs have at most 256 colors per frame, so the frames are quantized
+    /// as they are written.
+    ///
+    /// # Errors
+    /// Fails if the writer fails.
+    pub fn write_gif(self, writer: impl Write) -> Result<(), TilrError> {
+        let mut encoder = GifEncoder::new_with_speed(writer, 10);
+        encoder.set_repeat(Repeat::Infinite).map_err(TilrError::EncodeAnimation)?;
+        self.render_frames(|img, delay| {
+            encoder
+                .encode_frame(Frame::from_parts(img, 0, 0, delay))
+                .map_err(TilrError::EncodeAnimation)
+        })
+    }
+
+    /// Write the animation as an animated PNG that loops forever.
+    ///
+    /// # Errors
+    /// Fails if the writer fails.
+    pub fn write_apng(self, writer: impl Write) -> Result<(), TilrError> {
+        let (width, height) = self.output_size();
+        let mut encoder = png::Encoder::new(writer, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(self.frames.len() as u32, 0)?;
+        let mut writer = encoder.write_header()?;
+        self.render_frames(|img, delay| {
+            let (numerator, denominator) = delay.numer_denom_ms();
+            let millis = (numerator as f64 / denominator as f64).round().min(u16::MAX as f64) as u16;
+            writer.set_frame_delay(millis, 1000)?;
+            Ok(writer.write_image_data(img.as_raw())?)
+        })?;
+        Ok(writer.finish()?)
+    }
+}
+
+/// Decode the frames of an animated GIF or PNG, composited to the full size
+/// of the animation. Other images, including PNGs that aren't animated,
+/// decode to a single frame.
+///
+/// # Errors
+/// Fails if the file can't be read or decoded.
+pub fn load_frames(path: &Path) -> Result<Vec<Frame>, TilrError> {
+    let decode_err = |source| TilrError::Decode { path: path.to_path_buf(), source };
+    let open = || {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|source| TilrError::Io { path: path.to_path_buf(), source })
+    };
+
+    let frames = match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(open()?).and_then(|d| d.into_frames().collect_frames()),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(open()?).map_err(decode_err)?;
+            if !decoder.is_apng() {
+                return Ok(vec![Frame::new(load_image(path)?.into_rgba8())]);
+            }
+            decoder.apng().into_frames().collect_frames()
+        }
+        _ => return Ok(vec![Frame::new(load_image(path)?.into_rgba8())]),
+    };
+    frames.map_err(decode_err)
+}
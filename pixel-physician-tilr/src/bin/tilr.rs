@@ -0,0 +1,636 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! `tilr`: build a mosaic of an image out of a directory of image tiles.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use clap::{Args, Parser, ValueEnum};
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use pixel_physician_tilr::{
+    load_frames, load_image, load_tiles_from, Adaptive, AnimatedMosaic, Averaging, Channel, ColorAdjust,
+    ColorAdjustMode, ColorMetric, Dither, Grid, LoadOptions, Mosaic, MosaicBuilder, Normalize, PngSink, Progress,
+    RgbaBuffer, Selection, Shape, Sizing, Structure, TileCache, TileSet, TileSetOptions, Tiling, TilrError, Variants,
+};
+
+/// Build a mosaic of an image out of a directory of image tiles.
+#[derive(Parser, Debug)]
+#[command(name = "tilr", version)]
+struct Tilr {
+    /// The image to build a mosaic of. Animated GIFs and PNGs become
+    /// animated mosaics, written as GIF or PNG.
+    target: PathBuf,
+    /// The directory of images to use as tiles.
+    tiles: PathBuf,
+    /// Where to write the mosaic.
+    output: PathBuf,
+    /// Also search subdirectories of the tile directory.
+    #[arg(short, long)]
+    recursive: bool,
+    /// Only use tiles whose paths match this glob pattern, e.g. `*.jpg`.
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Never use tiles whose paths match this glob pattern.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Keep an index of the tile directory next to it, so later runs only
+    /// decode new or changed tiles.
+    #[arg(long)]
+    cache: bool,
+    /// Match on small thumbnails of the tiles, and decode the tiles at full
+    /// resolution only as the mosaic uses them, keeping at most COUNT of
+    /// them decoded at once.
+    #[arg(long, value_name = "COUNT")]
+    lazy: Option<usize>,
+    /// Also match each tile rotated by 90°, 180° and 270°.
+    #[arg(long)]
+    rotate: bool,
+    /// Also match each tile mirrored horizontally and vertically.
+    #[arg(long)]
+    flip: bool,
+    /// The size of each tile in the mosaic, as WIDTHxHEIGHT. Defaults to
+    /// the size of the first tile.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    tile_size: Option<(u32, u32)>,
+    /// The regions of each cell and tile that are compared, as
+    /// COLUMNSxROWS. Each cell covers this many pixels of the target.
+    #[arg(long, value_name = "SIZE", value_parser = parse_grid, default_value = "1x1")]
+    grid: (u32, u32),
+    /// How colors are compared.
+    #[arg(long, value_enum, default_value_t)]
+    metric: Metric,
+    /// How the colors of each region of a tile or cell are combined.
+    #[arg(long, value_enum, default_value_t)]
+    averaging: AveragingChoice,
+    /// The number of color clusters the dominant color is chosen from,
+    /// with `--averaging dominant`.
+    #[arg(long, value_name = "COUNT", default_value_t = 4)]
+    clusters: u32,
+    /// Also match tiles on the edges in them, with this weight against
+    /// color; most useful with a `--grid` larger than 1x1.
+    #[arg(long, value_name = "WEIGHT")]
+    structure: Option<f32>,
+    #[command(flatten)]
+    sizing: SizingArgs,
+    /// The most times any one tile may be used.
+    #[arg(long, value_name = "COUNT")]
+    max_uses: Option<usize>,
+    /// How many cells away the same tile may not be used again.
+    #[arg(long, value_name = "CELLS", default_value_t = 0)]
+    repeat_radius: u32,
+    /// Choose randomly among this many of the closest tiles.
+    #[arg(long, value_name = "COUNT", default_value_t = 1)]
+    top_k: usize,
+    /// The seed for random choices.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// How color error is spread between cells.
+    #[arg(long, value_enum, default_value_t)]
+    dither: DitherChoice,
+    /// Recolor each tile toward its cell.
+    #[arg(long, value_enum, value_name = "MODE")]
+    color_adjust: Option<AdjustChoice>,
+    /// How strongly to recolor tiles, from 0 to 1.
+    #[arg(long, value_name = "STRENGTH", default_value_t = 0.5)]
+    color_strength: f32,
+    /// Use this many tile sizes, with larger tiles over flat areas.
+    #[arg(long, value_name = "LEVELS")]
+    adaptive: Option<u32>,
+    /// How much the colors of a cell may vary before it is split into
+    /// smaller cells, with `--adaptive`.
+    #[arg(long, value_name = "THRESHOLD", default_value_t = 8.0)]
+    adaptive_threshold: f32,
+    /// The shape of the cells.
+    #[arg(long, value_enum, default_value_t)]
+    shape: ShapeChoice,
+    /// The color of the grout between cells, as hex RGB or RGBA.
+    #[arg(long, value_name = "COLOR", value_parser = parse_color, default_value = "000000")]
+    grout: [u8; 4],
+    /// The width of the grout between cells, in pixels.
+    #[arg(long, value_name = "PIXELS", default_value_t = 0.0)]
+    grout_width: f32,
+    /// With an animated target, keep the tile of each cell from one frame
+    /// to the next while its colors are within this distance of the cell's.
+    #[arg(long, value_name = "THRESHOLD")]
+    coherence: Option<f32>,
+    /// The format of the output image. Defaults to the format for the
+    /// output path's extension.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+    /// The precision the mosaic is built at: 8 or 16 bits per channel, or
+    /// floating point for HDR images. PNG and TIFF output keep 16 bits and
+    /// OpenEXR output keeps floating point; other formats are 8-bit.
+    #[arg(long, value_enum, default_value_t)]
+    depth: Depth,
+    /// Also write which tile was placed in each cell, as JSON, or as CSV
+    /// if the path ends in `.csv`.
+    #[arg(long, value_name = "PATH")]
+    map: Option<PathBuf>,
+    /// Don't print progress.
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// How large the mosaic is; by default, one cell per grid block of the target.
+#[derive(Args, Debug)]
+#[group(multiple = false)]
+struct SizingArgs {
+    /// Use about this many cells in total.
+    #[arg(long, value_name = "COUNT")]
+    cells: Option<u32>,
+    /// Use exactly this many cells, as COLUMNSxROWS.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    cells_per_side: Option<(u32, u32)>,
+    /// Make the mosaic exactly this many pixels, as WIDTHxHEIGHT.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    output_size: Option<(u32, u32)>,
+}
+
+impl SizingArgs {
+    fn sizing(&self) -> Sizing {
+        match (self.cells, self.cells_per_side, self.output_size) {
+            (Some(count), _, _) => Sizing::Cells(count),
+            (_, Some((columns, rows)), _) => Sizing::CellsPerSide { columns, rows },
+            (_, _, Some((width, height))) => Sizing::Output { width, height },
+            _ => Sizing::Native,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy)]
+enum Metric {
+    #[default]
+    Rgb,
+    DeltaE76,
+    DeltaE2000,
+    Oklab,
+}
+
+impl From<Metric> for ColorMetric {
+    fn from(metric: Metric) -> Self {
+        match metric {
+            Metric::Rgb => ColorMetric::Rgb,
+            Metric::DeltaE76 => ColorMetric::DeltaE76,
+            Metric::DeltaE2000 => ColorMetric::DeltaE2000,
+            Metric::Oklab => ColorMetric::Oklab,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy)]
+enum AveragingChoice {
+    #[default]
+    Mean,
+    Linear,
+    Median,
+    Dominant,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy)]
+enum DitherChoice {
+    #[default]
+    None,
+    FloydSteinberg,
+    Ordered,
+}
+
+impl From<DitherChoice> for Dither {
+    fn from(dither: DitherChoice) -> Self {
+        match dither {
+            DitherChoice::None => Dither::None,
+            DitherChoice::FloydSteinberg => Dither::FloydSteinberg,
+            DitherChoice::Ordered => Dither::Ordered { spread: 1.0 },
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum AdjustChoice {
+    MeanShift,
+    Gain,
+    HistogramMatch,
+    Overlay,
+}
+
+impl From<AdjustChoice> for ColorAdjustMode {
+    fn from(mode: AdjustChoice) -> Self {
+        match mode {
+            AdjustChoice::MeanShift => ColorAdjustMode::MeanShift,
+            AdjustChoice::Gain => ColorAdjustMode::Gain,
+            AdjustChoice::HistogramMatch => ColorAdjustMode::HistogramMatch,
+            AdjustChoice::Overlay => ColorAdjustMode::Overlay,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy)]
+enum ShapeChoice {
+    #[default]
+    Square,
+    Brick,
+    Hexagon,
+    Triangle,
+}
+
+impl From<ShapeChoice> for Shape {
+    fn from(shape: ShapeChoice) -> Self {
+        match shape {
+            ShapeChoice::Square => Shape::Square,
+            ShapeChoice::Brick => Shape::Brick,
+            ShapeChoice::Hexagon => Shape::Hexagon,
+            ShapeChoice::Triangle => Shape::Triangle,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Format {
+    Png,
+    Gif,
+    Jpeg,
+    Tiff,
+    Bmp,
+    Exr,
+}
+
+impl From<Format> for ImageFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Png => ImageFormat::Png,
+            Format::Gif => ImageFormat::Gif,
+            Format::Jpeg => ImageFormat::Jpeg,
+            Format::Tiff => ImageFormat::Tiff,
+            Format::Bmp => ImageFormat::Bmp,
+            Format::Exr => ImageFormat::OpenExr,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    #[default]
+    #[value(name = "8")]
+    Eight,
+    #[value(name = "16")]
+    Sixteen,
+    Float,
+}
+
+/// Parse a size given as `WIDTHxHEIGHT`, or as a single number for a square.
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+    let parse = |n: &str| n.trim().parse::<u32>().map_err(|e| format!("invalid size {s:?}: {e}"));
+    match s.split_once(['x', 'X', '×']) {
+        Some((width, height)) => Ok((parse(width)?, parse(height)?)),
+        None => parse(s).map(|n| (n, n)),
+    }
+}
+
+/// Parse a grid size as with [`parse_size`]; a grid needs at least one
+/// region across and down.
+fn parse_grid(s: &str) -> Result<(u32, u32), String> {
+    match parse_size(s)? {
+        (0, _) | (_, 0) => Err(format!("invalid grid {s:?}: must have at least one region across and down")),
+        grid => Ok(grid),
+    }
+}
+
+/// Parse a color given as hex `RRGGBB` or `RRGGBBAA`, with or without a `#`.
+fn parse_color(s: &str) -> Result<[u8; 4], String> {
+    let hex = s.trim_start_matches('#');
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return Err(format!("invalid color {s:?}: expected RRGGBB or RRGGBBAA"));
+    }
+    let mut color = [u8::MAX; 4];
+    for (c, i) in color.iter_mut().zip((0..hex.len()).step_by(2)) {
+        *c = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| format!("invalid color {s:?}: {e}"))?;
+    }
+    Ok(color)
+}
+
+fn main() -> ExitCode {
+    let args = Tilr::parse();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("tilr: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Tilr) -> Result<(), Box<dyn Error>> {
+    let format = match args.format {
+        Some(format) => format.into(),
+        None => ImageFormat::from_path(&args.output)?,
+    };
+    let mut frames = load_frames(&args.target)?;
+    if args.depth != Depth::Eight {
+        if frames.len() > 1 {
+            return Err("animations are always 8-bit; use --depth 8".into());
+        }
+        if args.cache {
+            return Err("the tile index only holds 8-bit tiles; use --depth 8 with --cache".into());
+        }
+    }
+
+    match args.depth {
+        Depth::Eight => {
+            let tiles = Arc::new(match args.cache {
+                true => load_cached(args)?,
+                false => load(args)?,
+            });
+            if frames.len() > 1 {
+                return animate(args, frames, &tiles, format);
+            }
+            still(args, frames.remove(0).into_buffer().into(), &tiles, format)
+        }
+        // the frame is 8-bit, so decode the target again at its own depth
+        Depth::Sixteen => still::<u16>(args, load_image(&args.target)?, &Arc::new(load(args)?), format),
+        Depth::Float => still::<f32>(args, load_image(&args.target)?, &Arc::new(load(args)?), format),
+    }
+}
+
+/// Build and write the mosaic of a still target.
+fn still<C: Channel>(
+    args: &Tilr,
+    target: DynamicImage,
+    tiles: &Arc<TileSet<C>>,
+    format: ImageFormat,
+) -> Result<(), Box<dyn Error>> {
+    let progress = |msg: &str| {
+        if !args.quiet {
+            eprintln!("{msg}");
+        }
+    };
+
+    let alpha = target.color().has_alpha()
+        && matches!(format, ImageFormat::Png | ImageFormat::Tiff | ImageFormat::OpenExr)
+        && target.pixels().any(|(_, _, px)| px.0[3] < u8::MAX);
+    let mut mosaic = build(args, target, tiles)?;
+    if !args.quiet {
+        mosaic = mosaic.with_progress(show_progress);
+    }
+
+    let (width, height) = mosaic.output_size();
+    progress(&format!("building a {width}×{height} mosaic..."));
+    if format == ImageFormat::Png {
+        // stream PNGs to disk, so the mosaic never has to fit in memory
+        let file = File::create(&args.output).map_err(|source| TilrError::Io {
+            path: args.output.clone(),
+            source,
+        })?;
+        let writer = BufWriter::new(file);
+        let mut sink = match args.depth {
+            Depth::Eight => PngSink::new(writer, width, height, alpha)?,
+            _ => PngSink::new_16bit(writer, width, height, alpha)?,
+        };
+        mosaic.render_strips(&mut sink)?;
+        sink.finish()?;
+    } else {
+        let mut img = RgbaBuffer::<C>::new(width, height);
+        let mut sink = |y: u32, strip: &RgbaBuffer<C>| {
+            image::imageops::replace(&mut img, strip, 0, y as i64);
+            Ok(())
+        };
+        mosaic.render_strips(&mut sink)?;
+        encodable(C::into_dynamic(img), format, alpha).save_with_format(&args.output, format)?;
+    }
+    progress(&format!("wrote {}", args.output.display()));
+
+    if let Some(path) = &args.map {
+        let file = File::create(path).map_err(|source| TilrError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let map = mosaic.placements()?;
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+            map.write_csv(BufWriter::new(file))?;
+        } else {
+            map.write_json(BufWriter::new(file))?;
+        }
+        progress(&format!("wrote {}", path.display()));
+    }
+
+    Ok(())
+}
+
+/// Convert a mosaic to the most precise color type the output format can
+/// hold, keeping alpha only if asked to.
+fn encodable(img: DynamicImage, format: ImageFormat, alpha: bool) -> DynamicImage {
+    let precise = !matches!(img, DynamicImage::ImageRgba8(_));
+    match (format, alpha) {
+        (ImageFormat::OpenExr, true) => img.into_rgba32f().into(),
+        (ImageFormat::OpenExr, false) => img.into_rgb32f().into(),
+        (ImageFormat::Png | ImageFormat::Tiff, true) if precise => img.into_rgba16().into(),
+        (ImageFormat::Png | ImageFormat::Tiff, false) if precise => img.into_rgb16().into(),
+        (_, true) => img.into_rgba8().into(),
+        (_, false) => img.into_rgb8().into(),
+    }
+}
+
+/// Set up the mosaic of a target image as asked.
+fn build<C: Channel>(
+    args: &Tilr,
+    target: impl Into<DynamicImage>,
+    tiles: &Arc<TileSet<C>>,
+) -> Result<Mosaic<C>, TilrError> {
+    let mut mosaic = MosaicBuilder::new(target, Arc::clone(tiles))
+        .with_sizing(args.sizing.sizing());
+    if let Some((width, height)) = args.tile_size {
+        mosaic = mosaic.with_tile_size(width, height);
+    }
+    let mut mosaic = mosaic.build()?.with_selection(Selection {
+        max_uses: args.max_uses,
+        repeat_radius: args.repeat_radius,
+        top_k: args.top_k.max(1),
+        seed: args.seed,
+        dither: args.dither.into(),
+    });
+    if let Some(mode) = args.color_adjust {
+        mosaic = mosaic.with_color_adjust(ColorAdjust {
+            mode: mode.into(),
+            strength: args.color_strength,
+        });
+    }
+    if let Some(levels) = args.adaptive {
+        mosaic = mosaic.with_adaptive(Adaptive {
+            levels,
+            threshold: args.adaptive_threshold,
+        });
+    }
+    Ok(mosaic.with_tiling(Tiling {
+        shape: args.shape.into(),
+        grout: image::Rgba(args.grout),
+        grout_width: args.grout_width,
+    }))
+}
+
+/// Build and write the mosaic of each frame of an animated target.
+fn animate(
+    args: &Tilr,
+    frames: Vec<image::Frame>,
+    tiles: &Arc<TileSet>,
+    format: ImageFormat,
+) -> Result<(), Box<dyn Error>> {
+    if !matches!(format, ImageFormat::Gif | ImageFormat::Png) {
+        return Err(format!("can't write an animation as {format:?}; use GIF or PNG").into());
+    }
+    if args.map.is_some() {
+        return Err("placement maps can't be written for animations".into());
+    }
+    let count = frames.len();
+    let frames = frames
+        .into_iter()
+        .map(|frame| {
+            let delay = frame.delay();
+            Ok((build(args, frame.into_buffer(), tiles)?, delay))
+        })
+        .collect::<Result<_, TilrError>>()?;
+    let mut animation = AnimatedMosaic::new(frames)?;
+    if let Some(threshold) = args.coherence {
+        animation = animation.with_coherence(threshold);
+    }
+
+    let (width, height) = animation.output_size();
+    if !args.quiet {
+        eprintln!("matching tiles for {count} frames of a {width}×{height} mosaic...");
+    }
+    let file = File::create(&args.output).map_err(|source| TilrError::Io {
+        path: args.output.clone(),
+        source,
+    })?;
+    let writer = BufWriter::new(file);
+    if format == ImageFormat::Gif {
+        animation.write_gif(writer)?;
+    } else {
+        animation.write_apng(writer)?;
+    }
+    if !args.quiet {
+        eprintln!("wrote {}", args.output.display());
+    }
+    Ok(())
+}
+
+/// The most pixels across and down of the thumbnails matched with `--lazy`.
+const THUMBNAIL_SIZE: u32 = 64;
+
+/// Get the size of the thumbnails to match, keeping the aspect ratio of
+/// the tiles, or the tile size itself without `--lazy`.
+fn thumbnail_size(args: &Tilr) -> Option<(u32, u32)> {
+    match (args.lazy, args.tile_size) {
+        (Some(_), Some((width, height))) if width.max(height) > THUMBNAIL_SIZE => {
+            let longest = width.max(height);
+            Some(((width * THUMBNAIL_SIZE / longest).max(1), (height * THUMBNAIL_SIZE / longest).max(1)))
+        }
+        (_, size) => size,
+    }
+}
+
+/// Get the options the tile set is built with.
+fn tile_options(args: &Tilr) -> TileSetOptions {
+    TileSetOptions {
+        metric: args.metric.into(),
+        averaging: match args.averaging {
+            AveragingChoice::Mean => Averaging::Mean,
+            AveragingChoice::Linear => Averaging::Linear,
+            AveragingChoice::Median => Averaging::Median,
+            AveragingChoice::Dominant => Averaging::Dominant { clusters: args.clusters },
+        },
+        grid: Grid::new(args.grid.0, args.grid.1),
+        normalize: Normalize {
+            size: thumbnail_size(args),
+            ..Default::default()
+        },
+        variants: Variants {
+            rotations: args.rotate,
+            flips: args.flip,
+        },
+        structure: args.structure.map(|weight| Structure { weight }),
+        ..Default::default()
+    }
+}
+
+/// Get which files under the tile directory are loaded.
+fn load_options(args: &Tilr) -> LoadOptions {
+    LoadOptions {
+        recursive: args.recursive,
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        thumbnail: args.lazy.map(|_| (THUMBNAIL_SIZE, THUMBNAIL_SIZE)),
+    }
+}
+
+/// Load the tile set at the given depth.
+fn load<C: Channel>(args: &Tilr) -> Result<TileSet<C>, Box<dyn Error>> {
+    if !args.quiet {
+        eprintln!("loading tiles...");
+    }
+    let loaded = load_tiles_from([&args.tiles], &load_options(args))?;
+    warn_skipped(&loaded.skipped);
+    let mut tiles = loaded.into_tile_set_of(tile_options(args))?;
+    if let Some(capacity) = args.lazy {
+        tiles = tiles.with_lazy_originals(capacity);
+    }
+    if !args.quiet {
+        eprintln!("loaded {} tiles", tiles.len());
+    }
+    Ok(tiles)
+}
+
+/// Load the 8-bit tile set through the tile index.
+fn load_cached(args: &Tilr) -> Result<TileSet, Box<dyn Error>> {
+    if !args.quiet {
+        eprintln!("loading tiles...");
+    }
+    let thumbnail_size = thumbnail_size(args).unwrap_or((THUMBNAIL_SIZE, THUMBNAIL_SIZE));
+    let mut cache =
+        TileCache::open(&args.tiles, tile_options(args), thumbnail_size)?.with_load_options(load_options(args));
+    let refresh = cache.refresh()?;
+    warn_skipped(&refresh.skipped);
+    cache.save()?;
+    let mut tiles = cache.tile_set()?;
+    if let Some(capacity) = args.lazy {
+        tiles = tiles.with_lazy_originals(capacity);
+    }
+    if !args.quiet {
+        eprintln!(
+            "tile index: {} reused, {} decoded, {} removed",
+            refresh.reused, refresh.decoded, refresh.removed
+        );
+        eprintln!("loaded {} tiles", tiles.len());
+    }
+    Ok(tiles)
+}
+
+/// Print the tiles that couldn't be loaded.
+fn warn_skipped(skipped: &[TilrError]) {
+    for e in skipped {
+        eprintln!("tilr: skipping {e}");
+    }
+}
+
+/// Show how far a mosaic has got, on one line per phase.
+fn show_progress(progress: Progress) {
+    eprint!("\r{}: {}/{}", progress.phase, progress.done, progress.total);
+    if progress.done == progress.total {
+        eprintln!();
+    }
+    let _ = io::stderr().flush();
+}
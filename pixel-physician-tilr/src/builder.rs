@@ -0,0 +1,184 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+
+use crate::error::TilrError;
+use crate::mosaic::Mosaic;
+use crate::normalize::{Fit, Normalize};
+use crate::pixel::Channel;
+use crate::tiles::TileSet;
+
+/// How large a mosaic built by a [`MosaicBuilder`] is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sizing {
+    /// One cell per block of [`Grid`](crate::Grid) pixels of the original
+    /// image, as with [`Mosaic::new`]. The image is only resampled if it
+    /// isn't a whole number of blocks across and down.
+    #[default]
+    Native,
+    /// An output image of this many pixels. The mosaic is rounded up to
+    /// whole tiles, then cropped to this size.
+    Output {
+        /// The width of the output image.
+        width: u32,
+        /// The height of the output image.
+        height: u32,
+    },
+    /// About this many cells in total, shaped to keep the aspect ratio of
+    /// the original image.
+    Cells(u32),
+    /// Exactly this many cells across and down.
+    CellsPerSide {
+        /// The number of cells across.
+        columns: u32,
+        /// The number of cells down.
+        rows: u32,
+    },
+}
+
+/// Builds a [`Mosaic`] of a given size, resampling the original image to
+/// the number of cells needed.
+///
+/// ```no_run
+/// # use pixel_physician_tilr::{MosaicBuilder, Sizing, TileSet};
+/// # fn build(img: image::RgbImage, tiles: TileSet) -> Result<(), pixel_physician_tilr::TilrError> {
+/// let mosaic = MosaicBuilder::new(img, tiles)
+///     .with_tile_size(32, 32)
+///     .with_sizing(Sizing::Cells(10_000))
+///     .build()?
+///     .into_image()?;
+/// # Ok(())
+/// # }
+/// ```
+#[allow(missing_debug_implementations)]
+pub struct MosaicBuilder<C: Channel = u8> {
+    /// The original image used to create the mosaic.
+    img: DynamicImage,
+    /// The set of tiles to build the mosaic from.
+    tiles: Arc<TileSet<C>>,
+    /// The size of a tile in the output image, if not the size of the tiles
+    /// in the set.
+    tile_size: Option<(u32, u32)>,
+    /// How large the mosaic is.
+    sizing: Sizing,
+    /// How the original image is brought to the aspect ratio of the cells.
+    fit: Fit,
+    /// The filter used to resample the original image.
+    filter: FilterType,
+}
+
+impl<C: Channel> MosaicBuilder<C> {
+    /// Start building a mosaic of the given image from the given tiles.
+    ///
+    /// By default, the mosaic uses the tiles at their own size (or at the
+    /// size of the first tile's file, with
+    /// [`TileSet::with_lazy_originals`]) and has one cell per block of
+    /// [`Grid`](crate::Grid) pixels of the image; the image is cropped, if
+    /// needed, to keep its aspect ratio.
+    ///
+    /// The tiles may be shared with other mosaics through an [`Arc`], e.g.
+    /// for the frames of an [`AnimatedMosaic`](crate::AnimatedMosaic).
+    pub fn new(img: impl Into<DynamicImage>, tiles: impl Into<Arc<TileSet<C>>>) -> Self {
+        Self {
+            img: img.into(),
+            tiles: tiles.into(),
+            tile_size: None,
+            sizing: Sizing::default(),
+            fit: Fit::default(),
+            filter: FilterType::Triangle,
+        }
+    }
+
+    /// Set the size of a tile in the output image.
+    pub fn with_tile_size(mut self, tile_width: u32, tile_height: u32) -> Self {
+        self.tile_size = Some((tile_width, tile_height));
+        self
+    }
+
+    /// Set how large the mosaic is.
+    pub fn with_sizing(mut self, sizing: Sizing) -> Self {
+        self.sizing = sizing;
+        self
+    }
+
+    /// Set how the original image is brought to the aspect ratio of the
+    /// cells when the two differ.
+    pub fn with_fit(mut self, fit: Fit) -> Self {
+        self.fit = fit;
+        self
+    }
+
+    /// Set the filter used to resample the original image.
+    pub fn with_filter(mut self, filter: FilterType) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Resample the original image to the number of cells needed and build
+    /// the [`Mosaic`].
+    ///
+    /// # Errors
+    /// Fails if the image, the tile size or the requested size has no
+    /// pixels or cells, or if the signature grid has no regions.
+    pub fn build(self) -> Result<Mosaic<C>, TilrError> {
+        let (width, height) = self.img.dimensions();
+        if width == 0 || height == 0 {
+            return Err(TilrError::ZeroSize("image"));
+        }
+        let (tile_width, tile_height) = self
+            .tile_size
+            .unwrap_or_else(|| self.tiles.render_size());
+        if tile_width == 0 || tile_height == 0 {
+            return Err(TilrError::ZeroSize("tile size"));
+        }
+
+        let grid = self.tiles.grid();
+        if grid.columns == 0 || grid.rows == 0 {
+            return Err(TilrError::ZeroSize("grid"));
+        }
+        let (columns, rows) = match self.sizing {
+            Sizing::Native => (width.div_ceil(grid.columns), height.div_ceil(grid.rows)),
+            Sizing::Output { width, height } => (width.div_ceil(tile_width), height.div_ceil(tile_height)),
+            Sizing::Cells(0) => (0, 0),
+            Sizing::Cells(count) => {
+                // columns / rows such that the mosaic has the image's aspect ratio
+                let ratio = (width as f64 * tile_height as f64) / (height as f64 * tile_width as f64);
+                let rows = (count as f64 / ratio).sqrt().round().max(1.0);
+                let columns = (count as f64 / rows).round().max(1.0);
+                (columns as u32, rows as u32)
+            }
+            Sizing::CellsPerSide { columns, rows } => (columns, rows),
+        };
+        if columns == 0 || rows == 0 {
+            return Err(TilrError::ZeroSize("mosaic size"));
+        }
+
+        let normalize = Normalize {
+            size: None,
+            fit: self.fit,
+            filter: self.filter,
+        };
+        let img = normalize.apply(self.img, columns * grid.columns, rows * grid.rows);
+        let mosaic = Mosaic::new(img, self.tiles, tile_width, tile_height)?;
+        Ok(match self.sizing {
+            Sizing::Output { width, height } => mosaic.crop_to(width, height),
+            _ => mosaic,
+        })
+    }
+}
@@ -0,0 +1,476 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use bincode::Options;
+use image::RgbaImage;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::error::TilrError;
+use crate::tiles::{Tile, TileSet, TileSetOptions};
+use crate::utils::{load_image, tile_paths, LoadOptions};
+use crate::variant::Variants;
+
+/// The version of the index file format; bump it whenever [`IndexFile`]
+/// or the way signatures are computed changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// The extension of the index file kept next to a tile directory.
+const EXTENSION: &str = "tilr-index";
+
+/// A persistent index of a directory of tiles, so that large tile
+/// libraries don't have to be decoded again on every run.
+///
+/// The index lives next to the tile directory (`photos/` is indexed in
+/// `photos.tilr-index`) and stores, for every tile, its path, modification
+/// time, size, signature and a small thumbnail. [`TileCache::refresh`]
+/// only decodes files that are new or have changed since the index was
+/// written, and [`TileCache::tile_set`] builds a [`TileSet`] straight from
+/// the thumbnails.
+///
+/// The thumbnails are the tiles of that [`TileSet`], so they should be at
+/// least as large as the tiles of the mosaic to avoid upscaling them, unless
+/// the set renders from the original files with
+/// [`TileSet::with_lazy_originals`].
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use pixel_physician_tilr::{TileCache, TileSetOptions};
+/// let mut cache = TileCache::open(Path::new("photos"), TileSetOptions::default(), (64, 64))?;
+/// cache.refresh()?;
+/// cache.save()?;
+/// let tiles = cache.tile_set()?;
+/// # Ok::<(), pixel_physician_tilr::TilrError>(())
+/// ```
+#[allow(missing_debug_implementations)]
+pub struct TileCache {
+    /// The directory of tiles being indexed.
+    dir: PathBuf,
+    /// The file the index is stored in.
+    path: PathBuf,
+    /// The options tiles are prepared with, including the thumbnail size.
+    options: TileSetOptions,
+    /// Which files in the directory are tiles.
+    load: LoadOptions,
+    /// The indexed tiles, in the order of their paths.
+    entries: Vec<Entry>,
+}
+
+/// How many tiles were reused, decoded or dropped by [`TileCache::refresh`].
+#[derive(Debug, Default)]
+pub struct Refresh {
+    /// Tiles whose files were unchanged, so weren't decoded again.
+    pub reused: usize,
+    /// Tiles that were new or had changed, so were decoded.
+    pub decoded: usize,
+    /// Tiles whose files no longer exist, or are no longer selected by
+    /// the [`LoadOptions`].
+    pub removed: usize,
+    /// The files and directories that couldn't be read or decoded, and were
+    /// left out of the index.
+    pub skipped: Vec<TilrError>,
+}
+
+/// The contents of an index file.
+#[derive(Serialize, Deserialize)]
+struct IndexFile<'a> {
+    /// The [`FORMAT_VERSION`] the file was written with.
+    version: u32,
+    /// A description of the options the entries were prepared with; the
+    /// entries are only reused if it matches.
+    options: String,
+    /// The indexed tiles.
+    entries: Cow<'a, [Entry]>,
+}
+
+/// A single tile in an index.
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The file the tile was loaded from.
+    path: PathBuf,
+    /// When the file was last modified, if the platform reports it.
+    modified: Option<Duration>,
+    /// The size of the file in bytes.
+    size: u64,
+    /// The signature of the tile.
+    signature: Vec<f32>,
+    /// The width of the thumbnail.
+    width: u32,
+    /// The height of the thumbnail.
+    height: u32,
+    /// The RGBA pixels of the thumbnail.
+    pixels: Vec<u8>,
+}
+
+impl Entry {
+    /// Check that the thumbnail is of the given size and holds exactly its
+    /// pixels, and that the signature has the given number of components.
+    fn is_consistent(&self, (width, height): (u32, u32), dims: usize) -> bool {
+        (self.width, self.height) == (width, height)
+            && self.pixels.len() == width as usize * height as usize * 4
+            && self.signature.len() == dims
+    }
+}
+
+impl TileCache {
+    /// Open the index of the given directory of tiles.
+    ///
+    /// Tiles are prepared with the given options, except that they are
+    /// always brought to `thumbnail_size` as if by
+    /// [`Normalize::size`](crate::Normalize::size). If there is no index
+    /// yet, or it was written with different options, the cache starts out
+    /// empty; call [`TileCache::refresh`] to fill it.
+    ///
+    /// # Errors
+    /// Fails if `dir` isn't a directory, if `thumbnail_size` has no pixels,
+    /// or if the signature grid has no regions.
+    pub fn open(dir: &Path, mut options: TileSetOptions, thumbnail_size: (u32, u32)) -> Result<Self, TilrError> {
+        if !dir.is_dir() {
+            return Err(TilrError::NotADirectory(dir.to_path_buf()));
+        }
+        if thumbnail_size.0 == 0 || thumbnail_size.1 == 0 {
+            return Err(TilrError::ZeroSize("thumbnail size"));
+        }
+        if options.grid.columns == 0 || options.grid.rows == 0 {
+            return Err(TilrError::ZeroSize("grid"));
+        }
+        options.normalize.size = Some(thumbnail_size);
+
+        let dir = fs::canonicalize(dir).map_err(|source| TilrError::Io { path: dir.to_path_buf(), source })?;
+        let path = match (dir.parent(), dir.file_name()) {
+            (Some(parent), Some(name)) => parent.join(format!("{}.{EXTENSION}", name.to_string_lossy())),
+            _ => dir.join(format!(".{EXTENSION}")),
+        };
+
+        // an unreadable, outdated or damaged index is simply rebuilt; limiting
+        // it to the size of the file keeps a damaged length from being allocated
+        let entries = File::open(&path)
+            .ok()
+            .and_then(|file| {
+                let limit = file.metadata().ok()?.len();
+                bincode::DefaultOptions::new()
+                    .with_fixint_encoding()
+                    .allow_trailing_bytes()
+                    .with_limit(limit)
+                    .deserialize_from::<_, IndexFile<'_>>(BufReader::new(file))
+                    .ok()
+            })
+            .filter(|index| index.version == FORMAT_VERSION && index.options == describe(&options))
+            .filter(|index| index.entries.iter().all(|e| e.is_consistent(thumbnail_size, options.dims())))
+            .map(|index| index.entries.into_owned())
+            .unwrap_or_default();
+
+        Ok(Self {
+            dir,
+            path,
+            options,
+            load: LoadOptions::default(),
+            entries,
+        })
+    }
+
+    /// Set which files in the directory are tiles, e.g. to include
+    /// subdirectories. Only the top level of the directory is indexed by
+    /// default.
+    pub fn with_load_options(mut self, load: LoadOptions) -> Self {
+        self.load = load;
+        self
+    }
+
+    /// Get the file the index is stored in.
+    pub fn index_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the number of indexed tiles.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether there are no indexed tiles.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bring the index up to date with the tile directory, decoding only
+    /// the files that are new or whose modification time or size changed.
+    ///
+    /// Files that can't be read or decoded are left out of the index and
+    /// listed in [`Refresh::skipped`]; they are tried again on the next
+    /// refresh.
+    ///
+    /// # Errors
+    /// Fails if the directory can't be listed or a pattern is invalid.
+    pub fn refresh(&mut self) -> Result<Refresh, TilrError> {
+        let mut known: HashMap<PathBuf, Entry> = self.entries.drain(..).map(|e| (e.path.clone(), e)).collect();
+        let mut stats = Refresh::default();
+
+        let mut entries = Vec::new();
+        let mut changed = Vec::new();
+        for path in tile_paths(&self.dir, &self.load, &mut stats.skipped)? {
+            if path == self.path {
+                continue;
+            }
+            let meta = match fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(source) => {
+                    stats.skipped.push(TilrError::Io { path, source });
+                    continue;
+                }
+            };
+            let modified = meta.modified().ok().and_then(|m| m.duration_since(UNIX_EPOCH).ok());
+            match known.remove(&path) {
+                Some(entry) if modified.is_some() && entry.modified == modified && entry.size == meta.len() => {
+                    stats.reused += 1;
+                    entries.push(Some(entry));
+                }
+                _ => {
+                    changed.push((entries.len(), path, modified, meta.len()));
+                    entries.push(None);
+                }
+            }
+        }
+        stats.removed = known.len();
+
+        let decoded: Vec<_> = changed
+            .into_par_iter()
+            .map(|(slot, path, modified, size)| (slot, self.prepare(path, modified, size)))
+            .collect();
+        for (slot, entry) in decoded {
+            match entry {
+                Ok(entry) => {
+                    stats.decoded += 1;
+                    entries[slot] = Some(entry);
+                }
+                Err(e) => stats.skipped.push(e),
+            }
+        }
+
+        self.entries = entries.into_iter().flatten().collect();
+        Ok(stats)
+    }
+
+    /// Decode a tile and compute its thumbnail and signature.
+    fn prepare(&self, path: PathBuf, modified: Option<Duration>, size: u64) -> Result<Entry, TilrError> {
+        let img = load_image(&path)?;
+        if img.width() == 0 || img.height() == 0 {
+            return Err(TilrError::ZeroSize("tile image"));
+        }
+        let (width, height) = self.options.normalize.size.expect("thumbnail size is always set");
+        let img = self.options.normalize.apply(img, width, height).into_rgba8();
+        let tile = Tile::new(img, &self.options);
+        Ok(Entry {
+            path,
+            modified,
+            size,
+            signature: tile.signature().to_vec(),
+            width,
+            height,
+            pixels: tile.img().as_raw().clone(),
+        })
+    }
+
+    /// Write the index next to the tile directory.
+    ///
+    /// The index is written to a temporary file first, so an interrupted
+    /// save never leaves a corrupt index behind.
+    ///
+    /// # Errors
+    /// Fails if the index file can't be written.
+    pub fn save(&self) -> Result<(), TilrError> {
+        let tmp = self.path.with_extension(format!("{EXTENSION}.tmp"));
+        let io_err = |source| TilrError::Io { path: tmp.clone(), source };
+        let index = IndexFile {
+            version: FORMAT_VERSION,
+            options: describe(&self.options),
+            entries: Cow::Borrowed(&self.entries),
+        };
+
+        let mut writer = BufWriter::new(File::create(&tmp).map_err(io_err)?);
+        bincode::serialize_into(&mut writer, &index)
+            .map_err(|source| TilrError::Index { path: self.path.clone(), source })?;
+        writer.into_inner().map_err(|e| io_err(e.into_error()))?;
+        fs::rename(&tmp, &self.path).map_err(|source| TilrError::Io { path: self.path.clone(), source })
+    }
+
+    /// Build a [`TileSet`] from the indexed tiles without decoding any files.
+    ///
+    /// Each [`Tile`] remembers the file it was loaded from; see [`Tile::path`].
+    ///
+    /// # Errors
+    /// Fails if there are no indexed tiles.
+    pub fn tile_set(&self) -> Result<TileSet, TilrError> {
+        let tiles = self
+            .entries
+            .iter()
+            .map(|e| {
+                let img = RgbaImage::from_raw(e.width, e.height, e.pixels.clone())
+                    .expect("thumbnail should match its dimensions");
+                Tile::from_parts(img, e.signature.clone(), Some(e.path.clone()))
+            })
+            .collect();
+        TileSet::from_tiles(tiles, self.options)
+    }
+}
+
+/// Describe the options that affect thumbnails and signatures, to tell
+/// whether an index can be reused.
+///
+/// Only the signatures of the original tiles are indexed, so the variants
+/// don't matter.
+fn describe(options: &TileSetOptions) -> String {
+    let options = TileSetOptions {
+        variants: Variants::default(),
+        ..*options
+    };
+    format!("{options:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::color::ColorMetric;
+
+    /// A directory of tiles that is removed, along with its index, once the
+    /// test is done with it.
+    struct TileDir {
+        /// The directory holding the tile directory and its index.
+        root: PathBuf,
+        /// The tile directory.
+        dir: PathBuf,
+    }
+
+    impl TileDir {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("tilr-cache-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            let dir = root.join("tiles");
+            fs::create_dir_all(&dir).unwrap();
+            Self { root, dir }
+        }
+
+        /// Write a flat-colored tile of the given size.
+        fn write(&self, name: &str, size: u32, color: [u8; 4]) {
+            RgbaImage::from_pixel(size, size, Rgba(color)).save(self.dir.join(name)).unwrap();
+        }
+
+        fn open(&self, options: TileSetOptions) -> TileCache {
+            TileCache::open(&self.dir, options, (4, 4)).unwrap()
+        }
+    }
+
+    impl Drop for TileDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn reuses_an_unchanged_index() {
+        let tiles = TileDir::new("unchanged");
+        tiles.write("a.png", 8, [255, 0, 0, 255]);
+        tiles.write("b.png", 8, [0, 255, 0, 255]);
+        tiles.write("c.png", 8, [0, 0, 255, 255]);
+
+        let mut cache = tiles.open(TileSetOptions::default());
+        assert!(cache.is_empty());
+        let refresh = cache.refresh().unwrap();
+        assert_eq!((refresh.reused, refresh.decoded, refresh.removed), (0, 3, 0));
+        cache.save().unwrap();
+        assert_eq!(cache.index_path(), tiles.root.canonicalize().unwrap().join("tiles.tilr-index"));
+
+        let mut cache = tiles.open(TileSetOptions::default());
+        assert_eq!(cache.len(), 3);
+        let refresh = cache.refresh().unwrap();
+        assert_eq!((refresh.reused, refresh.decoded, refresh.removed), (3, 0, 0));
+
+        let set = cache.tile_set().unwrap();
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.tiles()[0].path(), Some(tiles.dir.canonicalize().unwrap().join("a.png").as_path()));
+        assert_eq!(set.tiles()[1].img().get_pixel(0, 0).0, [0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn decodes_changed_tiles_again() {
+        let tiles = TileDir::new("changed");
+        tiles.write("a.png", 8, [255, 0, 0, 255]);
+        tiles.write("b.png", 8, [0, 255, 0, 255]);
+        tiles.write("c.png", 8, [0, 0, 255, 255]);
+        let mut cache = tiles.open(TileSetOptions::default());
+        cache.refresh().unwrap();
+        cache.save().unwrap();
+
+        // a different size changes the file size even within the resolution
+        // of the modification time
+        tiles.write("b.png", 16, [255, 255, 0, 255]);
+        fs::remove_file(tiles.dir.join("c.png")).unwrap();
+        tiles.write("d.png", 8, [0, 255, 255, 255]);
+
+        let mut cache = tiles.open(TileSetOptions::default());
+        let refresh = cache.refresh().unwrap();
+        assert_eq!((refresh.reused, refresh.decoded, refresh.removed), (1, 2, 1));
+        let set = cache.tile_set().unwrap();
+        let colors: Vec<[u8; 4]> = set.tiles().iter().map(|t| t.img().get_pixel(0, 0).0).collect();
+        assert_eq!(colors, [[255, 0, 0, 255], [255, 255, 0, 255], [0, 255, 255, 255]]);
+    }
+
+    #[test]
+    fn discards_an_index_made_with_other_options() {
+        let tiles = TileDir::new("options");
+        tiles.write("a.png", 8, [255, 0, 0, 255]);
+        let mut cache = tiles.open(TileSetOptions::default());
+        cache.refresh().unwrap();
+        cache.save().unwrap();
+
+        let options = TileSetOptions {
+            metric: ColorMetric::DeltaE76,
+            ..Default::default()
+        };
+        assert!(tiles.open(options).is_empty());
+        assert!(TileCache::open(&tiles.dir, TileSetOptions::default(), (2, 2)).unwrap().is_empty());
+        // the variants aren't indexed, so they don't matter
+        let options = TileSetOptions {
+            variants: Variants {
+                rotations: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(tiles.open(options).len(), 1);
+    }
+
+    #[test]
+    fn discards_an_index_with_inconsistent_entries() {
+        let tiles = TileDir::new("inconsistent");
+        tiles.write("a.png", 8, [255, 0, 0, 255]);
+        let mut cache = tiles.open(TileSetOptions::default());
+        cache.refresh().unwrap();
+        cache.entries[0].pixels.pop();
+        cache.save().unwrap();
+        assert!(tiles.open(TileSetOptions::default()).is_empty());
+
+        fs::write(cache.index_path(), b"not an index").unwrap();
+        assert!(tiles.open(TileSetOptions::default()).is_empty());
+    }
+}
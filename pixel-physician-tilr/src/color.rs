@@ -0,0 +1,373 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::OnceLock;
+
+use image::Rgb;
+
+/// A color expressed in the color space used by a [`ColorMetric`].
+pub(crate) type Color = [f32; 3];
+
+/// The distance used to compare pixels in the original image with
+/// the colors of the [`Tile`](crate::Tile)s in a [`TileSet`](crate::TileSet).
+///
+/// Each metric has an associated color space; tile colors are
+/// precomputed in that space when the [`TileSet`](crate::TileSet)
+/// is built so that matching only needs to convert the target pixels.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorMetric {
+    /// Euclidean distance between the raw sRGB values.
+    ///
+    /// This is the cheapest metric, but it is not perceptually
+    /// uniform, so dark colors in particular are matched poorly.
+    #[default]
+    Rgb,
+    /// The CIE 1976 color difference (ΔE*ab), i.e. the Euclidean
+    /// distance in CIELAB.
+    DeltaE76,
+    /// The CIEDE2000 color difference (ΔE00) in CIELAB.
+    ///
+    /// This is the most perceptually accurate metric, and also the
+    /// most expensive to compute.
+    DeltaE2000,
+    /// Euclidean distance in the Oklab color space.
+    Oklab,
+}
+
+impl ColorMetric {
+    /// Convert an sRGB pixel into the color space used by this metric.
+    pub(crate) fn to_space(self, px: &Rgb<u8>) -> Color {
+        match self {
+            ColorMetric::Rgb => px.0.map(f32::from),
+            ColorMetric::DeltaE76 | ColorMetric::DeltaE2000 => linear_to_lab(to_linear(px)),
+            ColorMetric::Oklab => linear_to_oklab(to_linear(px)),
+        }
+    }
+
+    /// Convert an sRGB color with channels in `[0, 1]`, or beyond for HDR
+    /// colors, into the color space used by this metric.
+    pub(crate) fn to_space_unit(self, rgb: Color) -> Color {
+        match self {
+            ColorMetric::Rgb => rgb.map(|c| c * 255.0),
+            ColorMetric::DeltaE76 | ColorMetric::DeltaE2000 => linear_to_lab(rgb.map(decode_srgb)),
+            ColorMetric::Oklab => linear_to_oklab(rgb.map(decode_srgb)),
+        }
+    }
+
+    /// Convert a linear-light RGB color into the color space used by this
+    /// metric.
+    pub(crate) fn linear_to_space(self, rgb: Color) -> Color {
+        match self {
+            ColorMetric::Rgb => rgb.map(|c| encode_srgb(c) * 255.0),
+            ColorMetric::DeltaE76 | ColorMetric::DeltaE2000 => linear_to_lab(rgb),
+            ColorMetric::Oklab => linear_to_oklab(rgb),
+        }
+    }
+
+    /// Compute the squared distance between two colors in the color
+    /// space used by this metric.
+    pub(crate) fn sq_dist(self, a: &Color, b: &Color) -> f32 {
+        match self {
+            ColorMetric::Rgb | ColorMetric::DeltaE76 | ColorMetric::Oklab => {
+                (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)
+            }
+            ColorMetric::DeltaE2000 => delta_e2000_sq(a, b),
+        }
+    }
+
+    /// Get the channels of this metric's color space that ordered dithering
+    /// should offset: all of them for sRGB, and only lightness otherwise, so
+    /// that dithering doesn't shift the hue.
+    pub(crate) fn dither_channels(self) -> [f32; 3] {
+        match self {
+            ColorMetric::Rgb => [1.0; 3],
+            ColorMetric::DeltaE76 | ColorMetric::DeltaE2000 | ColorMetric::Oklab => [1.0, 0.0, 0.0],
+        }
+    }
+
+    /// Get a lower bound on [`ColorMetric::sq_dist`] between any two colors
+    /// whose values in the given channel differ by `delta`, and whose first
+    /// channel lies within `lightness`, as the lowest and highest values.
+    ///
+    /// This lets spatial indexes prune candidates without assuming the
+    /// metric is Euclidean. A bound of zero means the channel can't be used
+    /// for pruning.
+    pub(crate) fn channel_lower_bound(self, channel: usize, delta: f32, (low, high): (f32, f32)) -> f32 {
+        match self {
+            ColorMetric::Rgb | ColorMetric::DeltaE76 | ColorMetric::Oklab => delta * delta,
+            // The chroma and hue terms of ΔE00 are never negative, and the
+            // lightness weighting S_L grows with the distance of the mean L*
+            // from 50, which is at most that of the lightest or darkest
+            // color. HDR colors can be far lighter than L* = 100, so the
+            // weighting isn't bounded in general. The weighting is raised
+            // slightly to allow for rounding.
+            ColorMetric::DeltaE2000 if channel == 0 => {
+                let extreme = if (low - 50.0).abs() > (high - 50.0).abs() { low } else { high };
+                (delta / (lightness_weight(extreme) * 1.001)).powi(2)
+            }
+            ColorMetric::DeltaE2000 => 0.0,
+        }
+    }
+}
+
+/// Decode an sRGB pixel into linear-light RGB values in `[0, 1]`.
+pub(crate) fn to_linear(px: &Rgb<u8>) -> Color {
+    static LUT: OnceLock<[f32; 256]> = OnceLock::new();
+    let lut = LUT.get_or_init(|| std::array::from_fn(|v| decode_srgb(v as f32 / 255.0)));
+    px.0.map(|c| lut[c as usize])
+}
+
+/// Decode an sRGB channel value into linear light.
+pub(crate) fn decode_srgb(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode a linear-light channel value as sRGB.
+fn encode_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Convert linear-light RGB (D65) into CIELAB.
+fn linear_to_lab([r, g, b]: Color) -> Color {
+    const EPSILON: f32 = 216.0 / 24389.0;
+    const KAPPA: f32 = 24389.0 / 27.0;
+    let f = |t: f32| {
+        if t > EPSILON {
+            t.cbrt()
+        } else {
+            (KAPPA * t + 16.0) / 116.0
+        }
+    };
+
+    // normalize by the D65 reference white
+    let x = (0.412_456_4 * r + 0.357_576_1 * g + 0.180_437_5 * b) / 0.950_47;
+    let y = 0.212_672_9 * r + 0.715_152_2 * g + 0.072_175 * b;
+    let z = (0.019_333_9 * r + 0.119_192 * g + 0.950_304_1 * b) / 1.088_83;
+
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Convert linear-light RGB into Oklab.
+fn linear_to_oklab([r, g, b]: Color) -> Color {
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+/// Compute the weighting S_L that CIEDE2000 gives to differences in
+/// lightness between colors of the given mean lightness.
+fn lightness_weight(l_bar: f32) -> f32 {
+    let l_off = (l_bar - 50.0).powi(2);
+    1.0 + 0.015 * l_off / (20.0 + l_off).sqrt()
+}
+
+/// Compute the square of the CIEDE2000 color difference between two CIELAB colors.
+fn delta_e2000_sq(&[l1, a1, b1]: &Color, &[l2, a2, b2]: &Color) -> f32 {
+    const POW_25_7: f32 = 6_103_515_625.0;
+    // hue angle in degrees, in [0, 360)
+    let hue = |b: f32, a: f32| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let c_bar7 = c_bar.powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + POW_25_7)).sqrt());
+    let (a1p, a2p) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1p, c2p) = (a1p.hypot(b1), a2p.hypot(b2));
+    let (h1p, h2p) = (hue(b1, a1p), hue(b2, a2p));
+    let chroma_zero = c1p * c2p == 0.0;
+
+    let d_lp = l2 - l1;
+    let d_cp = c2p - c1p;
+    let d_hp = if chroma_zero {
+        0.0
+    } else {
+        let d = h2p - h1p;
+        if d > 180.0 {
+            d - 360.0
+        } else if d < -180.0 {
+            d + 360.0
+        } else {
+            d
+        }
+    };
+    let d_big_hp = 2.0 * (c1p * c2p).sqrt() * (d_hp / 2.0).to_radians().sin();
+
+    let l_bar_p = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+    let h_bar_p = if chroma_zero {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let cos_deg = |d: f32| d.to_radians().cos();
+    let t = 1.0 - 0.17 * cos_deg(h_bar_p - 30.0)
+        + 0.24 * cos_deg(2.0 * h_bar_p)
+        + 0.32 * cos_deg(3.0 * h_bar_p + 6.0)
+        - 0.20 * cos_deg(4.0 * h_bar_p - 63.0);
+    let d_theta = 30.0 * (-((h_bar_p - 275.0) / 25.0).powi(2)).exp();
+    let c_bar_p7 = c_bar_p.powi(7);
+    let r_c = 2.0 * (c_bar_p7 / (c_bar_p7 + POW_25_7)).sqrt();
+    let s_l = lightness_weight(l_bar_p);
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (d_lp / s_l, d_cp / s_c, d_big_hp / s_h);
+    l * l + c * c + h * h + r_t * c * h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The CIELAB color pairs and their CIEDE2000 differences from Sharma,
+    /// Wu and Dalal, "The CIEDE2000 Color-Difference Formula: Implementation
+    /// Notes, Supplementary Test Data, and Mathematical Observations" (2005).
+    const SHARMA: [(Color, Color, f32); 34] = [
+        ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+        ([50.0, 3.1571, -77.2803], [50.0, 0.0, -82.7485], 2.8615),
+        ([50.0, 2.8361, -74.0200], [50.0, 0.0, -82.7485], 3.4412),
+        ([50.0, -1.3802, -84.2814], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -1.1848, -84.8006], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, -0.9009, -85.5211], [50.0, 0.0, -82.7485], 1.0000),
+        ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+        ([50.0, -1.0, 2.0], [50.0, 0.0, 0.0], 2.3669),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0009], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0010], 7.1792),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0011], 7.2195),
+        ([50.0, 2.4900, -0.0010], [50.0, -2.4900, 0.0012], 7.2195),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0009, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0010, -2.4900], 4.8045),
+        ([50.0, -0.0010, 2.4900], [50.0, 0.0011, -2.4900], 4.7461),
+        ([50.0, 2.5000, 0.0], [50.0, 0.0, -2.5000], 4.3065),
+        ([50.0, 2.5000, 0.0], [73.0, 25.0, -18.0], 27.1492),
+        ([50.0, 2.5000, 0.0], [61.0, -5.0, 29.0], 22.8977),
+        ([50.0, 2.5000, 0.0], [56.0, -27.0, -3.0], 31.9030),
+        ([50.0, 2.5000, 0.0], [58.0, 24.0, 15.0], 19.4535),
+        ([50.0, 2.5000, 0.0], [50.0, 3.1736, 0.5854], 1.0000),
+        ([50.0, 2.5000, 0.0], [50.0, 3.2972, 0.0], 1.0000),
+        ([50.0, 2.5000, 0.0], [50.0, 1.8634, 0.5757], 1.0000),
+        ([50.0, 2.5000, 0.0], [50.0, 3.2592, 0.3350], 1.0000),
+        ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+        ([63.0109, -31.0961, -5.8663], [62.8187, -29.7946, -4.0864], 1.2630),
+        ([61.2901, 3.7196, -5.3901], [61.4292, 2.2480, -4.9620], 1.8731),
+        ([35.0831, -44.1164, 3.7933], [35.0232, -40.0716, 1.5901], 1.8645),
+        ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+        ([36.4612, 47.8580, 18.3852], [36.2715, 50.5065, 21.2231], 1.4146),
+        ([90.8027, -2.0831, 1.4410], [91.1528, -1.6435, 0.0447], 1.4441),
+        ([90.9257, -0.5406, -0.9208], [88.6381, -0.8985, -0.7239], 1.5381),
+        ([6.7747, -0.2908, -2.4247], [5.8714, -0.0985, -2.2286], 0.6377),
+        ([2.0776, 0.0795, -1.1350], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+
+    fn assert_close(actual: Color, expected: Color, tolerance: f32) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() <= tolerance, "expected {expected:?}, got {actual:?}");
+        }
+    }
+
+    #[test]
+    fn delta_e2000_matches_reference_data() {
+        for (i, (a, b, expected)) in SHARMA.iter().enumerate() {
+            for (a, b) in [(a, b), (b, a)] {
+                let actual = ColorMetric::DeltaE2000.sq_dist(a, b).sqrt();
+                assert!((actual - expected).abs() < 1e-3, "pair {}: expected {expected}, got {actual}", i + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn delta_e2000_lightness_bound_holds() {
+        let metric = ColorMetric::DeltaE2000;
+        // HDR colors are lighter than L* = 100
+        let hdr = [1.0, 2.0, 4.0, 16.0].map(|v| metric.to_space_unit([v, v * 0.9, v * 0.8]));
+        let pairs = SHARMA
+            .iter()
+            .map(|&(a, b, _)| (a, b))
+            .chain(hdr.iter().flat_map(|&a| hdr.iter().map(move |&b| (a, b))));
+        for (a, b) in pairs {
+            let bound = metric.channel_lower_bound(0, a[0] - b[0], (a[0].min(b[0]), a[0].max(b[0])));
+            assert!(bound <= metric.sq_dist(&a, &b), "{a:?} and {b:?}");
+        }
+    }
+
+    #[test]
+    fn delta_e76_is_euclidean_in_lab() {
+        let metric = ColorMetric::DeltaE76;
+        let white = metric.to_space(&Rgb([255, 255, 255]));
+        let black = metric.to_space(&Rgb([0, 0, 0]));
+        assert_close(white, [100.0, 0.0, 0.0], 0.01);
+        assert_close(black, [0.0, 0.0, 0.0], 0.01);
+        assert!((metric.sq_dist(&white, &black).sqrt() - 100.0).abs() < 0.01);
+
+        let red = metric.to_space(&Rgb([255, 0, 0]));
+        assert_close(red, [53.24, 80.09, 67.20], 0.05);
+        let expected = (53.24f32.powi(2) + 80.09f32.powi(2) + 67.20f32.powi(2)).sqrt();
+        assert!((metric.sq_dist(&red, &black).sqrt() - expected).abs() < 0.05);
+        assert_eq!(metric.sq_dist(&red, &red), 0.0);
+    }
+
+    #[test]
+    fn oklab_matches_reference_colors() {
+        let metric = ColorMetric::Oklab;
+        assert_close(metric.to_space(&Rgb([255, 255, 255])), [1.0, 0.0, 0.0], 1e-3);
+        assert_close(metric.to_space(&Rgb([0, 0, 0])), [0.0, 0.0, 0.0], 1e-6);
+        assert_close(metric.to_space(&Rgb([255, 0, 0])), [0.627_955, 0.224_863, 0.125_846], 1e-3);
+        assert_close(metric.to_space(&Rgb([0, 0, 255])), [0.452_014, -0.032_457, -0.311_528], 1e-3);
+
+        // grays have no chroma, and get lighter with their value
+        let grays: Vec<Color> = [32, 128, 224].iter().map(|&v| metric.to_space(&Rgb([v; 3]))).collect();
+        for gray in &grays {
+            assert_close([gray[1], gray[2], 0.0], [0.0; 3], 1e-3);
+        }
+        assert!(grays.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+    }
+
+    #[test]
+    fn unit_and_linear_conversions_agree_with_pixels() {
+        let px = Rgb([200, 120, 40]);
+        for metric in [ColorMetric::Rgb, ColorMetric::DeltaE76, ColorMetric::DeltaE2000, ColorMetric::Oklab] {
+            let expected = metric.to_space(&px);
+            let tolerance = if metric == ColorMetric::Rgb { 0.01 } else { 1e-3 };
+            assert_close(metric.to_space_unit(px.0.map(|c| c as f32 / 255.0)), expected, tolerance);
+            assert_close(metric.linear_to_space(to_linear(&px)), expected, tolerance);
+        }
+    }
+}
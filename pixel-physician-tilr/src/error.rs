@@ -0,0 +1,107 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::signature::Grid;
+
+/// Errors from building a [`Mosaic`](crate::Mosaic) or loading its tiles.
+#[derive(Error, Debug)]
+pub enum TilrError {
+    /// A file or directory couldn't be read.
+    #[error("failed to read {}: {source}", path.display())]
+    Io {
+        /// The file or directory that failed.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: io::Error,
+    },
+    /// An image file couldn't be decoded.
+    #[error("failed to decode {}: {source}", path.display())]
+    Decode {
+        /// The file that failed.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: image::ImageError,
+    },
+    /// A tile index couldn't be written.
+    #[error("failed to write tile index {}: {source}", path.display())]
+    Index {
+        /// The index file that failed.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: bincode::Error,
+    },
+    /// A glob pattern for selecting tile files is invalid.
+    #[error("invalid pattern {pattern:?}: {source}")]
+    Pattern {
+        /// The pattern that failed.
+        pattern: String,
+        /// The underlying error.
+        #[source]
+        source: glob::PatternError,
+    },
+    /// The mosaic couldn't be encoded.
+    #[error("failed to encode the mosaic: {0}")]
+    Encode(#[from] png::EncodingError),
+    /// An animated mosaic couldn't be encoded.
+    #[error("failed to encode the animation: {0}")]
+    EncodeAnimation(#[source] image::ImageError),
+    /// A placement map couldn't be read or written, or is invalid.
+    #[error("failed to read or write placement map: {0}")]
+    Map(#[source] io::Error),
+    /// A placement map refers to a tile that isn't in the tile set.
+    #[error("tile {tile}{} is not in the tile set", path.as_ref().map(|p| format!(" ({})", p.display())).unwrap_or_default())]
+    MissingTile {
+        /// The index of the tile in the map.
+        tile: usize,
+        /// The file of the tile in the map, if known.
+        path: Option<PathBuf>,
+    },
+    /// A path that should be a directory of tiles isn't one.
+    #[error("path must be a directory: {}", .0.display())]
+    NotADirectory(PathBuf),
+    /// There were no tiles to build a [`TileSet`](crate::TileSet) from.
+    #[error("no tiles to build the mosaic from")]
+    EmptyTileSet,
+    /// An image or size that must have pixels has none.
+    #[error("{0} must not be empty")]
+    ZeroSize(&'static str),
+    /// A frame of an animation isn't the same size as the first frame.
+    #[error("frame {frame} is not the same size as the first frame")]
+    FrameSizeMismatch {
+        /// The index of the frame.
+        frame: usize,
+    },
+    /// The original image can't be divided evenly into cells.
+    #[error("image of {width}×{height} pixels is not a multiple of the {}×{} grid", grid.columns, grid.rows)]
+    DimensionMismatch {
+        /// The width of the image.
+        width: u32,
+        /// The height of the image.
+        height: u32,
+        /// The grid each cell of the mosaic covers.
+        grid: Grid,
+    },
+    /// The work was abandoned through a [`CancelToken`](crate::CancelToken).
+    #[error("cancelled")]
+    Cancelled,
+}
@@ -0,0 +1,364 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::color::ColorMetric;
+
+/// The largest number of points stored in a single leaf of a [`KdTree`].
+const LEAF_SIZE: usize = 8;
+
+/// A k-d tree over the color signatures of the [`Tile`](crate::Tile)s in a
+/// [`TileSet`](crate::TileSet), used for exact nearest-neighbour queries.
+///
+/// Points are stored flattened as runs of color channels, so each axis of
+/// the tree is one channel of one color, optionally followed by axes that
+/// are compared by plain Euclidean distance. The tree never assumes that the
+/// [`ColorMetric`] is Euclidean: a subtree is only skipped when the metric
+/// guarantees, via [`ColorMetric::channel_lower_bound`], that nothing in it
+/// can beat the best match found so far.
+#[derive(Debug)]
+pub(crate) struct KdTree {
+    /// The number of axes of each point.
+    dims: usize,
+    /// The number of axes of each point that are colors compared by the
+    /// metric; any axes after these are compared by Euclidean distance.
+    color_dims: usize,
+    /// The lowest and highest value of the first channel of any color of
+    /// any point, which bounds how the metric weighs lightness.
+    lightness: (f32, f32),
+    /// The coordinates of all points, `dims` at a time.
+    coords: Vec<f32>,
+    /// The point indices, ordered so that each node covers a contiguous range.
+    order: Vec<usize>,
+    /// The nodes of the tree; the root is the first node.
+    nodes: Vec<Node>,
+}
+
+/// A single node in a [`KdTree`].
+#[derive(Debug)]
+enum Node {
+    /// A bucket of points, as a range into [`KdTree::order`].
+    Leaf { start: usize, end: usize },
+    /// A split along `axis` at `value`. Points on the `left` side have a
+    /// coordinate at most `value`, points on the `right` side at least `value`.
+    Split {
+        axis: usize,
+        value: f32,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl KdTree {
+    /// Build a tree over the given points, each of which has `dims` axes,
+    /// of which the first `color_dims` are colors.
+    ///
+    /// Only axes that the metric can bound are used to split the tree; if
+    /// there are none, queries degrade to a linear scan.
+    pub(crate) fn new(coords: Vec<f32>, dims: usize, color_dims: usize, metric: ColorMetric) -> Self {
+        let count = coords.len().checked_div(dims).unwrap_or(0);
+        let lightness = coords
+            .chunks_exact(dims)
+            .flat_map(|point| point[..color_dims].iter().step_by(3))
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &l| (lo.min(l), hi.max(l)));
+        let split_axes: Vec<usize> = (0..dims)
+            .filter(|&axis| axis >= color_dims || metric.channel_lower_bound(axis % 3, 1.0, lightness) > 0.0)
+            .collect();
+
+        let mut tree = Self {
+            dims,
+            color_dims,
+            lightness,
+            coords,
+            order: (0..count).collect(),
+            nodes: Vec::new(),
+        };
+        tree.build(0, count, &split_axes);
+        tree
+    }
+
+    /// Recursively build the node covering `order[start..end]`, returning its index.
+    fn build(&mut self, start: usize, end: usize, split_axes: &[usize]) -> usize {
+        let node_idx = self.nodes.len();
+        self.nodes.push(Node::Leaf { start, end });
+        if end - start <= LEAF_SIZE {
+            return node_idx;
+        }
+
+        // split along the axis with the largest spread
+        let Some((axis, spread)) = split_axes
+            .iter()
+            .map(|&axis| {
+                let (min, max) = self.order[start..end]
+                    .iter()
+                    .map(|&p| self.coord(p, axis))
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), c| (lo.min(c), hi.max(c)));
+                (axis, max - min)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+        else {
+            return node_idx;
+        };
+        if spread <= 0.0 {
+            return node_idx;
+        }
+
+        let mid = start + (end - start) / 2;
+        let (dims, coords) = (self.dims, &self.coords);
+        self.order[start..end].select_nth_unstable_by(mid - start, |&a, &b| {
+            coords[a * dims + axis].total_cmp(&coords[b * dims + axis])
+        });
+        let value = self.coord(self.order[mid], axis);
+
+        let left = self.build(start, mid, split_axes);
+        let right = self.build(mid, end, split_axes);
+        self.nodes[node_idx] = Node::Split { axis, value, left, right };
+        node_idx
+    }
+
+    /// Get the coordinate of point `p` along `axis`.
+    fn coord(&self, p: usize, axis: usize) -> f32 {
+        self.coords[p * self.dims + axis]
+    }
+
+    /// Get all the coordinates of point `p`.
+    pub(crate) fn point(&self, p: usize) -> &[f32] {
+        &self.coords[p * self.dims..(p + 1) * self.dims]
+    }
+
+    /// Compute the squared distance between a query and point `p`, summing
+    /// the metric's distance over each color in the point, and the squared
+    /// differences of any other axes.
+    pub(crate) fn sq_dist(&self, query: &[f32], p: usize, metric: ColorMetric) -> f32 {
+        let (query_colors, query_rest) = query.split_at(self.color_dims);
+        let (colors, rest) = self.point(p).split_at(self.color_dims);
+        let color: f32 = query_colors
+            .chunks_exact(3)
+            .zip(colors.chunks_exact(3))
+            .map(|(q, c)| metric.sq_dist(&[q[0], q[1], q[2]], &[c[0], c[1], c[2]]))
+            .sum();
+        color + query_rest.iter().zip(rest).map(|(q, c)| (q - c).powi(2)).sum::<f32>()
+    }
+
+    /// Get a lower bound on the squared distance between a query and any
+    /// point whose coordinates along `axis` differ from it by `delta`.
+    fn lower_bound(&self, query: &[f32], axis: usize, delta: f32, metric: ColorMetric) -> f32 {
+        if axis >= self.color_dims {
+            return delta * delta;
+        }
+        let l = query[axis - axis % 3];
+        let lightness = (self.lightness.0.min(l), self.lightness.1.max(l));
+        metric.channel_lower_bound(axis % 3, delta, lightness)
+    }
+
+    /// Find the point closest to `query` under the given metric, along with
+    /// its squared distance. Returns `None` only if the tree is empty.
+    pub(crate) fn nearest(&self, query: &[f32], metric: ColorMetric) -> Option<(usize, f32)> {
+        self.nearest_k(query, 1, metric, |_| true).into_iter().next()
+    }
+
+    /// Find the `k` points closest to `query` under the given metric, out of
+    /// those for which `accept` returns `true`, along with their squared
+    /// distances. The closest point comes first.
+    pub(crate) fn nearest_k(
+        &self,
+        query: &[f32],
+        k: usize,
+        metric: ColorMetric,
+        accept: impl Fn(usize) -> bool,
+    ) -> Vec<(usize, f32)> {
+        let mut best = Vec::with_capacity(k + 1);
+        if k > 0 && !self.nodes.is_empty() {
+            self.search(0, &Search { query, k, metric, accept: &accept }, &mut best);
+        }
+        best
+    }
+
+    /// Search the subtree at `node` for points closer than those in `best`.
+    fn search(&self, node: usize, search: &Search<'_>, best: &mut Vec<(usize, f32)>) {
+        match self.nodes[node] {
+            Node::Leaf { start, end } => {
+                for &p in &self.order[start..end] {
+                    if !(search.accept)(p) {
+                        continue;
+                    }
+                    let dist = self.sq_dist(search.query, p, search.metric);
+                    if dist < search.worst(best) {
+                        let at = best.partition_point(|&(_, d)| d <= dist);
+                        best.insert(at, (p, dist));
+                        best.truncate(search.k);
+                    }
+                }
+            }
+            Node::Split { axis, value, left, right } => {
+                let delta = search.query[axis] - value;
+                let (near, far) = if delta <= 0.0 { (left, right) } else { (right, left) };
+                self.search(near, search, best);
+
+                let bound = self.lower_bound(search.query, axis, delta.abs(), search.metric);
+                if bound < search.worst(best) {
+                    self.search(far, search, best);
+                }
+            }
+        }
+    }
+}
+
+/// The parameters of a single [`KdTree::nearest_k`] query.
+struct Search<'a> {
+    query: &'a [f32],
+    k: usize,
+    metric: ColorMetric,
+    accept: &'a dyn Fn(usize) -> bool,
+}
+
+impl Search<'_> {
+    /// Get the distance a point must beat to be one of the best found so far.
+    fn worst(&self, best: &[(usize, f32)]) -> f32 {
+        if best.len() < self.k {
+            f32::INFINITY
+        } else {
+            best[best.len() - 1].1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METRICS: [ColorMetric; 4] = [
+        ColorMetric::Rgb,
+        ColorMetric::DeltaE76,
+        ColorMetric::DeltaE2000,
+        ColorMetric::Oklab,
+    ];
+
+    /// Generate `count` points of `dims` axes, of which the first
+    /// `color_dims` are plausible colors for the metric. Values are coarsely
+    /// quantized, and some points are repeated, so that many distances tie.
+    fn points(count: usize, dims: usize, color_dims: usize, metric: ColorMetric, seed: u64) -> Vec<f32> {
+        let mut state = seed;
+        let mut next = move |steps: u64| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % steps) as f32 / (steps - 1) as f32
+        };
+        let mut coords = Vec::with_capacity(count * dims);
+        for _ in 0..count {
+            if coords.len() >= dims && next(4) == 0.0 {
+                let from = coords.len() - dims;
+                coords.extend_from_within(from..);
+                continue;
+            }
+            for axis in 0..dims {
+                let t = next(6);
+                coords.push(match (axis < color_dims, axis % 3, metric) {
+                    (false, ..) => t * 10.0,
+                    (true, _, ColorMetric::Rgb) => t * 255.0,
+                    (true, 0, ColorMetric::Oklab) => t,
+                    (true, _, ColorMetric::Oklab) => t * 0.6 - 0.3,
+                    (true, 0, _) => t * 100.0,
+                    (true, ..) => t * 160.0 - 80.0,
+                });
+            }
+        }
+        coords
+    }
+
+    /// Find the `k` best distances by comparing against every point.
+    fn linear_scan(
+        tree: &KdTree,
+        query: &[f32],
+        k: usize,
+        metric: ColorMetric,
+        accept: impl Fn(usize) -> bool,
+    ) -> Vec<f32> {
+        let count = tree.coords.len() / tree.dims;
+        let mut dists: Vec<f32> = (0..count)
+            .filter(|&p| accept(p))
+            .map(|p| tree.sq_dist(query, p, metric))
+            .collect();
+        dists.sort_by(f32::total_cmp);
+        dists.truncate(k);
+        dists
+    }
+
+    fn check(tree: &KdTree, query: &[f32], k: usize, metric: ColorMetric, accept: impl Fn(usize) -> bool + Copy) {
+        let found = tree.nearest_k(query, k, metric, accept);
+        let expected = linear_scan(tree, query, k, metric, accept);
+        let dists: Vec<f32> = found.iter().map(|&(_, d)| d).collect();
+        assert_eq!(dists, expected, "{metric:?}, k = {k}, query {query:?}");
+
+        // among ties any point may be returned, but each only once, and
+        // with its own distance
+        let mut indices: Vec<usize> = found.iter().map(|&(p, _)| p).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        assert_eq!(indices.len(), found.len());
+        for &(p, d) in &found {
+            assert!(accept(p));
+            assert_eq!(tree.sq_dist(query, p, metric), d);
+        }
+    }
+
+    #[test]
+    fn nearest_k_matches_linear_scan() {
+        for metric in METRICS {
+            for (dims, color_dims) in [(3, 3), (12, 12), (11, 3)] {
+                let tree = KdTree::new(points(300, dims, color_dims, metric, 0x9e37_79b9), dims, color_dims, metric);
+                let queries = points(40, dims, color_dims, metric, 0x1234_5678);
+                for query in queries.chunks_exact(dims) {
+                    for k in [1, 2, 5, 17, 400] {
+                        check(&tree, query, k, metric, |_| true);
+                        check(&tree, query, k, metric, |p| p % 3 != 0);
+                    }
+                    let nearest = tree.nearest(query, metric).map(|(_, d)| d);
+                    assert_eq!(nearest, linear_scan(&tree, query, 1, metric, |_| true).first().copied());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_k_matches_linear_scan_for_hdr_colors() {
+        // lightness beyond 100 weighs differences in lightness less under ΔE00
+        for metric in METRICS {
+            let grays: Vec<f32> = (0..=80)
+                .flat_map(|v| metric.to_space_unit([v as f32 * 0.05; 3]))
+                .collect();
+            let tree = KdTree::new(grays, 3, 3, metric);
+            for q in 0..200 {
+                let query = metric.to_space_unit([q as f32 * 0.0201 + 0.001; 3]);
+                for k in [1, 3] {
+                    check(&tree, &query, k, metric, |_| true);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_k_handles_empty_trees_and_queries() {
+        for metric in METRICS {
+            let tree = KdTree::new(Vec::new(), 3, 3, metric);
+            assert!(tree.nearest(&[0.0; 3], metric).is_none());
+
+            let tree = KdTree::new(points(20, 3, 3, metric, 7), 3, 3, metric);
+            assert!(tree.nearest_k(&[0.0; 3], 0, metric, |_| true).is_empty());
+            assert!(tree.nearest_k(&[0.0; 3], 3, metric, |_| false).is_empty());
+        }
+    }
+}
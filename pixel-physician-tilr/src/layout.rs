@@ -0,0 +1,217 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Cow;
+
+use crate::color::ColorMetric;
+use crate::pixel::{alpha, Channel, RgbaBuffer};
+use crate::shape::{masked_crop, Polygon, Shape};
+use crate::signature::{Grid, Rect};
+
+/// Options for an adaptive [`Mosaic`](crate::Mosaic), which uses larger
+/// tiles for flat areas of the original image and smaller tiles for
+/// detailed ones.
+///
+/// The mosaic starts out with cells `2^(levels - 1)` times the base tile
+/// size, and splits each cell into four, quadtree style, for as long as
+/// the colors it covers vary too much.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adaptive {
+    /// The number of cell sizes to use. `1` disables splitting.
+    pub levels: u32,
+    /// The largest standard deviation of the colors in a cell, in the units
+    /// of the [`ColorMetric`](crate::ColorMetric)'s color space, for which
+    /// the cell is not split further.
+    pub threshold: f32,
+}
+
+/// A single cell of a mosaic, made up of one or more base cells.
+///
+/// A base cell covers one block of [`Grid`] pixels in the original image,
+/// and one tile in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Cell {
+    /// The column of the top-left base cell covered by this cell.
+    pub(crate) x: u32,
+    /// The row of the top-left base cell covered by this cell.
+    pub(crate) y: u32,
+    /// The number of base cells this cell spans, both across and down.
+    pub(crate) span: u32,
+}
+
+impl Cell {
+    /// Get the area of the original image covered by this cell.
+    pub(crate) fn target_area(&self, grid: Grid) -> Rect {
+        Rect {
+            x: self.x * grid.columns,
+            y: self.y * grid.rows,
+            width: self.span * grid.columns,
+            height: self.span * grid.rows,
+        }
+    }
+
+    /// Get the area of the output image covered by this cell.
+    pub(crate) fn output_area(&self, tile_width: u32, tile_height: u32) -> Rect {
+        Rect {
+            x: self.x * tile_width,
+            y: self.y * tile_height,
+            width: self.span * tile_width,
+            height: self.span * tile_height,
+        }
+    }
+}
+
+/// The arrangement of the cells of a mosaic over a grid of base cells.
+///
+/// For [`Shape::Square`] cells, the position of a cell is the base cell at
+/// its top-left corner. For other shapes, it is an index into a grid of
+/// cells of that shape, as laid out by [`Shape::polygon`].
+#[derive(Debug)]
+pub(crate) struct Layout {
+    /// The shape of the cells.
+    pub(crate) shape: Shape,
+    /// The number of columns of cell positions.
+    pub(crate) columns: u32,
+    /// The number of rows of cell positions.
+    pub(crate) rows: u32,
+    /// The cells, in row-major order of their positions.
+    pub(crate) cells: Vec<Cell>,
+}
+
+impl Layout {
+    /// Lay out one cell per base cell.
+    pub(crate) fn uniform(columns: u32, rows: u32) -> Self {
+        let cells = (0..rows)
+            .flat_map(|y| (0..columns).map(move |x| Cell { x, y, span: 1 }))
+            .collect();
+        Self { shape: Shape::Square, columns, rows, cells }
+    }
+
+    /// Lay out cells of the given shape over a grid of base cells, with
+    /// every cell that overlaps the grid.
+    pub(crate) fn shaped(shape: Shape, columns: u32, rows: u32) -> Self {
+        let (index_columns, index_rows) = shape.index_size(columns, rows);
+        let cells = (0..index_rows)
+            .flat_map(|y| (0..index_columns).map(move |x| Cell { x, y, span: 1 }))
+            .filter(|cell| {
+                let [left, top, right, bottom] = shape.polygon(cell.x, cell.y, cell.span).bounds();
+                left < columns as f64 && top < rows as f64 && right > 0.0 && bottom > 0.0
+            })
+            .collect();
+        Self { shape, columns: index_columns, rows: index_rows, cells }
+    }
+
+    /// Lay out cells of varying sizes, splitting each cell for as long as
+    /// the colors of the original image it covers vary by more than the
+    /// threshold.
+    pub(crate) fn adaptive<C: Channel>(
+        img: &RgbaBuffer<C>,
+        columns: u32,
+        rows: u32,
+        grid: Grid,
+        metric: ColorMetric,
+        adaptive: &Adaptive,
+    ) -> Self {
+        let top_span = 1 << adaptive.levels.clamp(1, 16).saturating_sub(1);
+        let mut cells = Vec::new();
+        let mut pending: Vec<Cell> = (0..rows.div_ceil(top_span))
+            .flat_map(|y| (0..columns.div_ceil(top_span)).map(move |x| Cell { x: x * top_span, y: y * top_span, span: top_span }))
+            .collect();
+
+        while let Some(cell) = pending.pop() {
+            if cell.x >= columns || cell.y >= rows {
+                continue;
+            }
+            // cells hanging over the edge must be split to fit
+            let fits = cell.x + cell.span <= columns && cell.y + cell.span <= rows;
+            if cell.span == 1 || (fits && std_dev(img, cell.target_area(grid), metric) <= adaptive.threshold) {
+                cells.push(cell);
+                continue;
+            }
+
+            let span = cell.span / 2;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                pending.push(Cell { x: cell.x + dx * span, y: cell.y + dy * span, span });
+            }
+        }
+
+        cells.sort_unstable_by_key(|c| (c.y, c.x));
+        Self { shape: Shape::Square, columns, rows, cells }
+    }
+
+    /// Get the outline of a cell, in base cells.
+    pub(crate) fn polygon(&self, cell: &Cell) -> Polygon {
+        self.shape.polygon(cell.x, cell.y, cell.span)
+    }
+
+    /// Get the index of the cell at each position, in row-major order.
+    ///
+    /// Every position a larger cell covers maps to that cell.
+    pub(crate) fn owners(&self) -> Vec<Option<usize>> {
+        let mut owners = vec![None; self.columns as usize * self.rows as usize];
+        for (idx, cell) in self.cells.iter().enumerate() {
+            for y in cell.y..(cell.y + cell.span).min(self.rows) {
+                for x in cell.x..(cell.x + cell.span).min(self.columns) {
+                    owners[y as usize * self.columns as usize + x as usize] = Some(idx);
+                }
+            }
+        }
+        owners
+    }
+
+    /// Get the pixels of the original image that a cell covers, along with
+    /// the area of the returned image to sample.
+    ///
+    /// Square cells borrow the image itself. Other shapes get a copy of
+    /// their bounding box, in which pixels outside the cell barely count;
+    /// see [`masked_crop`].
+    pub(crate) fn target<'a, C: Channel>(
+        &self,
+        img: &'a RgbaBuffer<C>,
+        cell: &Cell,
+        grid: Grid,
+    ) -> (Cow<'a, RgbaBuffer<C>>, Rect) {
+        if self.shape == Shape::Square {
+            return (Cow::Borrowed(img), cell.target_area(grid));
+        }
+        let polygon = self.polygon(cell).scaled(grid.columns as f64, grid.rows as f64);
+        let crop = masked_crop(img, &polygon);
+        let area = Rect::of(&crop);
+        (Cow::Owned(crop), area)
+    }
+}
+
+/// Compute the standard deviation of the colors in an area of an image, in
+/// the color space of the given metric, weighting pixels by their alpha.
+fn std_dev<C: Channel>(img: &RgbaBuffer<C>, area: Rect, metric: ColorMetric) -> f32 {
+    let mut sum = [0f64; 3];
+    let mut sum_sq = [0f64; 3];
+    let mut weight = 0f64;
+    for y in area.y..area.y + area.height {
+        for x in area.x..area.x + area.width {
+            let px = img.get_pixel(x, y);
+            let alpha = alpha::<C>(px);
+            for (c, v) in C::to_space(px, metric).into_iter().enumerate() {
+                sum[c] += alpha * v as f64;
+                sum_sq[c] += alpha * (v as f64).powi(2);
+            }
+            weight += alpha;
+        }
+    }
+
+    let weight = weight.max(f64::MIN_POSITIVE);
+    let variance: f64 = (0..3).map(|c| sum_sq[c] / weight - (sum[c] / weight).powi(2)).sum();
+    variance.max(0.0).sqrt() as f32
+}
@@ -22,9 +22,46 @@
     broken_intra_doc_links
 )]
 
+mod adjust;
+mod animation;
+mod builder;
+mod cache;
+mod color;
+mod error;
+mod index;
+mod layout;
+mod lru;
 mod mosaic;
+mod normalize;
+mod output;
+mod pixel;
+mod placement;
+mod progress;
+mod selection;
+mod shape;
+mod signature;
+mod structure;
 mod tiles;
 mod utils;
+mod variant;
 
+pub use adjust::{ColorAdjust, ColorAdjustMode};
+pub use animation::{load_frames, AnimatedMosaic};
+pub use builder::{MosaicBuilder, Sizing};
+pub use cache::{Refresh, TileCache};
+pub use color::ColorMetric;
+pub use error::TilrError;
+pub use layout::Adaptive;
 pub use mosaic::Mosaic;
-pub use utils::load_tiles;
+pub use normalize::{Fit, Normalize};
+pub use output::{PngSink, StripSink};
+pub use pixel::{Channel, RgbaBuffer};
+pub use placement::{Placement, PlacementMap};
+pub use progress::{CancelToken, Phase, Progress};
+pub use selection::{Dither, Selection};
+pub use shape::{Shape, Tiling};
+pub use signature::{Averaging, Grid};
+pub use structure::Structure;
+pub use tiles::{Tile, TileSet, TileSetOptions};
+pub use utils::{load_image, load_tiles, load_tiles_from, LoadOptions, LoadedTiles};
+pub use variant::{Variant, Variants};
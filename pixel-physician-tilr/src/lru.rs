@@ -0,0 +1,103 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map that holds at most a fixed number of entries, dropping the entry
+/// used least recently to make room for a new one.
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    /// The most entries the map holds.
+    capacity: usize,
+    /// The entries, each with the time it was last used.
+    entries: HashMap<K, (V, u64)>,
+    /// The key of each entry by the time it was last used, oldest first.
+    order: BTreeMap<u64, K>,
+    /// The time of the latest use of any entry.
+    clock: u64,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Lru<K, V> {
+    /// Create an empty map that holds at most `capacity` entries.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Get the value for a key, marking it as just used.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.entries.get_mut(key)?;
+        let key = self.order.remove(used).expect("every entry should be in the order");
+        self.clock += 1;
+        *used = self.clock;
+        self.order.insert(self.clock, key);
+        Some(value.clone())
+    }
+
+    /// Add a value for a key, dropping the least recently used entry if the
+    /// map is full.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, used)) = self.entries.get(&key) {
+            self.order.remove(used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, key.clone());
+        self.entries.insert(key, (value, self.clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some("a"));
+        lru.insert(3, "c");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some("a"));
+        assert_eq!(lru.get(&3), Some("c"));
+
+        // replacing a value counts as a use, and doesn't drop anything
+        lru.insert(1, "d");
+        lru.insert(4, "e");
+        assert_eq!(lru.get(&3), None);
+        assert_eq!(lru.get(&1), Some("d"));
+        assert_eq!(lru.get(&4), Some("e"));
+        assert_eq!(lru.entries.len(), lru.order.len());
+    }
+
+    #[test]
+    fn holds_nothing_without_capacity() {
+        let mut lru = Lru::new(0);
+        lru.insert(1, "a");
+        assert_eq!(lru.get(&1), None);
+    }
+}
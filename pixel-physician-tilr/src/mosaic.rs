@@ -13,8 +13,24 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::adjust::ColorAdjust;
+use crate::error::TilrError;
+use crate::layout::{Adaptive, Cell, Layout};
+use crate::output::StripSink;
+use crate::pixel::{alpha, scale_alpha, Channel, RgbaBuffer};
+use crate::placement::{Placement, PlacementMap};
+use crate::progress::{CancelToken, Monitor, Phase, Progress};
+use crate::selection::Selection;
+use crate::shape::{render_shaped_strips, Shape, Tiling};
 use crate::tiles::*;
-use image::{DynamicImage, GenericImage, RgbImage};
+use crate::signature::{is_visible, Rect};
+use image::{DynamicImage, GenericImageView, RgbImage, RgbaImage};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelExtend, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 
 /// Generates an image 'mosaic' using a set of image Tiles.
 ///
@@ -22,101 +38,574 @@ use image::{DynamicImage, GenericImage, RgbImage};
 /// images in place of pixels. Using the average color of an image
 /// Tile, a suitable large image mosaic viewed from far enough
 /// away can appear to be a normal image.
+///
+/// The mosaic is built and rendered with channels of the same type as its
+/// [`TileSet`]: 8-bit by default, or 16-bit or floating point to keep the
+/// precision of the tiles and the original image.
 #[allow(missing_debug_implementations)]
-pub struct Mosaic {
-    /// The original image used to create the mosaic.
-    img: RgbImage,
+pub struct Mosaic<C: Channel = u8> {
+    /// The original image used to create the mosaic, converted to
+    /// channels of type `C`.
+    img: RgbaBuffer<C>,
     /// The set of [`Tile`]s to use to build the mosaic.
     ///
-    /// Pixels in the original image are mapped to these tiles based
-    /// on the Euclidean distance between the RGB pixel values and the
-    /// average RGB values in the [`Tile`].
-    tiles: TileSet,
-    /// An inner member used to build the resulting image mosaic.
-    inner: Inner,
+    /// Cells of the original image are mapped to these tiles based
+    /// on the distance, according to the set's [`ColorMetric`](crate::ColorMetric),
+    /// between the colors of the cell and the signature of the [`Tile`].
+    tiles: Arc<TileSet<C>>,
+    /// How tiles are chosen for each cell of the mosaic.
+    selection: Selection,
+    /// How each tile is recolored toward its cell, if at all.
+    color_adjust: Option<ColorAdjust>,
+    /// How cells are merged into larger ones over flat areas, if at all.
+    adaptive: Option<Adaptive>,
+    /// The shape of the cells, and the grout between them.
+    tiling: Tiling,
+    /// The width of a tile in the output image.
+    tile_width: u32,
+    /// The height of a tile in the output image.
+    tile_height: u32,
+    /// The size of the output image, which may crop the last row and
+    /// column of tiles.
+    output_size: (u32, u32),
+    /// The tiles chosen for the cells of the previous frame of an
+    /// animation, and how far the colors of a cell may be from its tile
+    /// for the tile to be kept.
+    previous: Option<(HashMap<Cell, usize>, f32)>,
+    /// Where progress is reported, and how the work is cancelled.
+    monitor: Monitor,
+    /// The tiles chosen for each cell, once they have been.
+    plan: OnceLock<Plan>,
 }
 
-impl Mosaic {
+impl<C: Channel> Mosaic<C> {
     /// Initialize a new image mosaic.
     ///
     /// # Arguments
-    /// * `img` - The original image used to create the mosaic.
-    /// * `tiles` - The set of Tiles to use to build the mosaic.
-    /// * `img_scaling` - The scaling factor to apply to the original
-    ///                   image for the mosaic. A scaling factor of `1`
-    ///                   means no scaling. The scaling performed does
-    ///                   _not_ preserve aspect ratio.
-    /// * `tile_size` - The desired side length for the Tiles to use
-    ///                 to generate this mosaic. If the Tiles are not
-    ///                 already squares with this side length, they will
-    ///                 be resized (without preserving aspect ratio) to
-    ///                 be squares with the given side length.
+    /// * `img` - The original image used to create the mosaic. Each
+    ///   cell of the mosaic corresponds to one block of pixels the size
+    ///   of the tile set's [`Grid`](crate::Grid), so with the default
+    ///   `1×1` grid each pixel becomes one tile. Transparent areas of
+    ///   the image stay transparent in the mosaic.
+    /// * `tiles` - The set of Tiles to use to build the mosaic, which
+    ///   also determines the [`ColorMetric`](crate::ColorMetric) and
+    ///   [`Grid`](crate::Grid) used for matching. It may be shared with
+    ///   other mosaics through an [`Arc`].
+    /// * `tile_width`, `tile_height` - The desired size for the Tiles
+    ///   to use to generate this mosaic. If the Tiles are not already
+    ///   this size, they will be resized (without preserving aspect
+    ///   ratio) to it.
+    ///
+    /// To scale the image to a given number of cells or output size
+    /// instead, use a [`MosaicBuilder`](crate::MosaicBuilder).
     ///
     /// # Returns
-    /// An empty mosaic. To build the mosaic, call [Mosaic::into_image].
+    /// An empty mosaic. To build the mosaic, call [Mosaic::into_image], or
+    /// [Mosaic::render_strips] for mosaics too large to fit in memory.
     /// Note that generating the resulting mosaic is an expensive operation and
-    /// could take many seconds (or minutes for especially large mosaics).
+    /// could take many seconds (or minutes for especially large mosaics);
+    /// use [Mosaic::with_progress] and [Mosaic::with_cancel] to follow or
+    /// abandon it.
+    ///
+    /// # Errors
+    /// Fails if the image or the tile size has no pixels, if the signature
+    /// grid has no regions, or if the image isn't a whole number of grid
+    /// blocks across and down.
     pub fn new(
-        img: RgbImage,
-        tiles: Vec<DynamicImage>,
+        img: impl Into<DynamicImage>,
+        tiles: impl Into<Arc<TileSet<C>>>,
         tile_width: u32,
         tile_height: u32,
-    ) -> Self {
-        // Build the tileset
-        let tiles = TileSet::from(tiles);
+    ) -> Result<Self, TilrError> {
+        let img = C::from_dynamic(img.into());
+        let tiles = tiles.into();
+        if img.width() == 0 || img.height() == 0 {
+            return Err(TilrError::ZeroSize("image"));
+        }
+        if tile_width == 0 || tile_height == 0 {
+            return Err(TilrError::ZeroSize("tile size"));
+        }
+        let grid = tiles.grid();
+        if grid.columns == 0 || grid.rows == 0 {
+            return Err(TilrError::ZeroSize("grid"));
+        }
+        if img.width() % grid.columns != 0 || img.height() % grid.rows != 0 {
+            return Err(TilrError::DimensionMismatch {
+                width: img.width(),
+                height: img.height(),
+                grid,
+            });
+        }
+
+        let (columns, rows) = tiles.cells_in(&img);
+        Ok(Self {
+            output_size: (columns * tile_width, rows * tile_height),
+            img,
+            tiles,
+            selection: Selection::default(),
+            color_adjust: None,
+            adaptive: None,
+            tiling: Tiling::default(),
+            tile_width,
+            tile_height,
+            previous: None,
+            monitor: Monitor::default(),
+            plan: OnceLock::new(),
+        })
+    }
+
+    /// Set how tiles are chosen for the cells of the mosaic, e.g. to limit
+    /// how often the same tile is repeated.
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self.plan = OnceLock::new();
+        self
+    }
+
+    /// Recolor each tile toward the cell of the original image it is
+    /// placed in, so that poorly matched tiles stand out less.
+    pub fn with_color_adjust(mut self, color_adjust: ColorAdjust) -> Self {
+        self.color_adjust = Some(color_adjust);
+        self
+    }
+
+    /// Use larger tiles, resampled from the tile set, for flat areas of the
+    /// original image and the regular tile size only for detailed areas.
+    pub fn with_adaptive(mut self, adaptive: Adaptive) -> Self {
+        self.adaptive = Some(adaptive);
+        self.plan = OnceLock::new();
+        self
+    }
+
+    /// Shape the cells of the mosaic as hexagons, bricks or triangles
+    /// instead of squares, or put grout between them.
+    ///
+    /// Cells of other shapes than [`Shape::Square`] are always one base cell
+    /// in size, so [`Mosaic::with_adaptive`] has no effect on them.
+    pub fn with_tiling(mut self, tiling: Tiling) -> Self {
+        self.tiling = tiling;
+        self.plan = OnceLock::new();
+        self
+    }
+
+    /// Call the given function as the mosaic is built, with the phase it is
+    /// in and how many cells of that phase are done.
+    ///
+    /// The function is called from the threads doing the work, up to a
+    /// hundred times per phase, so it should be quick; sending the
+    /// [`Progress`] over a channel is a good way to hand it to a UI.
+    pub fn with_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.monitor = self.monitor.with_report(progress);
+        self
+    }
+
+    /// Abandon building the mosaic once the given token is cancelled, in
+    /// which case it fails with [`TilrError::Cancelled`].
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.monitor = self.monitor.with_cancel(cancel);
+        self
+    }
+
+    /// Keep the tile chosen for each cell of the previous frame of an
+    /// animation, as long as its distance from the colors of the cell is at
+    /// most `threshold`.
+    pub(crate) fn with_previous(mut self, previous: HashMap<Cell, usize>, threshold: f32) -> Self {
+        self.previous = Some((previous, threshold));
+        self.plan = OnceLock::new();
+        self
+    }
 
-        // Initialize the inner image (the output mosaic image)
-        let (img_x, img_y) = img.dimensions();
-        let (mos_x, mos_y) = (img_x * tile_width, img_y * tile_height);
-        let inner = Inner(RgbImage::new(mos_x, mos_y));
+    /// Get the candidate chosen for each cell; see [`TileSet::candidate`].
+    pub(crate) fn chosen(&self) -> Result<HashMap<Cell, usize>, TilrError> {
+        let plan = self.plan()?;
+        Ok(plan.layout.cells.iter().copied().zip(plan.tiles.iter().copied()).collect())
+    }
 
-        Self { img, tiles, inner }
+    /// Check whether this mosaic uses the same [`TileSet`] as another.
+    pub(crate) fn shares_tiles(&self, other: &Mosaic<C>) -> bool {
+        Arc::ptr_eq(&self.tiles, &other.tiles)
     }
 
-    /// Generate the image mosaic and convert it to an [`RgbImage`].
+    /// Generate the image mosaic and convert it to an 8-bit [`RgbImage`].
     ///
     /// Depending on the size of the mosaic to build, this function may
-    /// take some time to run.
-    pub fn into_image(self) -> RgbImage {
-        let map = self.tiles.map_to(&self.img);
-        let (img_x, img_y) = self.img.dimensions();
-        let tile_width = self.tiles.tile_x_len();
-        let tile_height = self.tiles.tile_y_len();
-        let mut mosaic = self.inner;
-
-        // Build the mosaic
-        let mut mos_x = 0;
-        for x in 0..img_x {
-            let mut mos_y = 0;
-            for y in 0..img_y {
-                // Add the tile to the mosaic
-                let tile_for_px = map.get(&self.img.get_pixel(x, y)).expect("No tile for px");
-                mosaic.add_tile(tile_for_px, (mos_x, mos_y));
-
-                // Move to the next row in the mosaic
-                mos_y += tile_height;
+    /// take some time to run; see [`Mosaic::with_progress`] and
+    /// [`Mosaic::with_cancel`].
+    ///
+    /// # Errors
+    /// Fails if the mosaic is cancelled.
+    pub fn into_image(self) -> Result<RgbImage, TilrError> {
+        Ok(C::into_dynamic(self.into_buffer()?).into_rgb8())
+    }
+
+    /// Get the width and height of the mosaic in pixels.
+    pub fn output_size(&self) -> (u32, u32) {
+        self.output_size
+    }
+
+    /// Crop the output image to the given size, which must be no larger
+    /// than the full rows and columns of tiles.
+    pub(crate) fn crop_to(mut self, width: u32, height: u32) -> Self {
+        let (full_width, full_height) = self.output_size;
+        self.output_size = (width.min(full_width), height.min(full_height));
+        self
+    }
+
+    /// Generate the image mosaic and convert it to an 8-bit [`RgbaImage`].
+    ///
+    /// Areas where the original image is transparent are transparent in the
+    /// mosaic too, as are transparent parts of the tiles if their
+    /// [`TileSet`] has no background color.
+    ///
+    /// # Errors
+    /// Fails if the mosaic is cancelled.
+    pub fn into_rgba_image(self) -> Result<RgbaImage, TilrError> {
+        Ok(C::into_dynamic(self.into_buffer()?).into_rgba8())
+    }
+
+    /// Generate the image mosaic with channels of the same type as its
+    /// [`TileSet`], as with [`Mosaic::into_rgba_image`].
+    ///
+    /// Use this rather than [`Mosaic::into_rgba_image`] to keep the
+    /// precision of 16-bit or floating-point tiles.
+    ///
+    /// # Errors
+    /// Fails if the mosaic is cancelled.
+    pub fn into_buffer(self) -> Result<RgbaBuffer<C>, TilrError> {
+        let (width, height) = self.output_size();
+        let mut mosaic = RgbaBuffer::new(width, height);
+        if !self.tiling.is_plain() {
+            self.render_shaped(&mut |y: u32, strip: &RgbaBuffer<C>| {
+                image::imageops::replace(&mut mosaic, strip, 0, y as i64);
+                Ok(())
+            })?;
+            return Ok(mosaic);
+        }
+
+        // cells are copied straight into each strip of the mosaic as their
+        // row is reached, so only the cells of the current row are held
+        let tile_size = (self.tile_width, self.tile_height);
+        let mut strips = mosaic.chunks_mut(width as usize * self.tile_height as usize * 4);
+        self.for_each_row(|row, cells| {
+            let strip = strips.next().expect("mosaic should have a strip for every row");
+            fill_strip(strip, width, row, tile_size, cells);
+            Ok(())
+        })?;
+        Ok(mosaic)
+    }
+
+    /// Generate the image mosaic one row of cells at a time, passing each
+    /// strip of the mosaic to the given sink.
+    ///
+    /// Only one strip, [`Mosaic::output_size`] wide and one tile high (the
+    /// last may be shorter if the mosaic is cropped), is
+    /// held in memory at a time (along with any larger cells from
+    /// [`Mosaic::with_adaptive`] that span it), so this can build mosaics
+    /// far larger than [`Mosaic::into_rgba_image`] could.
+    ///
+    /// # Errors
+    /// Fails if the sink fails, or if the mosaic is cancelled.
+    pub fn render_strips(&self, sink: &mut impl StripSink<C>) -> Result<(), TilrError> {
+        if !self.tiling.is_plain() {
+            return self.render_shaped(sink);
+        }
+        let (width, height) = self.output_size;
+        let tile_size = (self.tile_width, self.tile_height);
+        self.for_each_row(|row, cells| {
+            let y = row * self.tile_height;
+            let mut strip = RgbaBuffer::new(width, self.tile_height.min(height - y));
+            fill_strip(&mut strip, width, row, tile_size, cells);
+            sink.write_strip(y, &strip)
+        })
+    }
+
+    /// Render the cells of the mosaic one row at a time, as with
+    /// [`for_each_row`], reporting progress and checking for cancellation.
+    fn for_each_row(
+        &self,
+        mut visit: impl FnMut(u32, &[(&Cell, Arc<RgbaBuffer<C>>)]) -> Result<(), TilrError>,
+    ) -> Result<(), TilrError> {
+        let plan = self.plan()?;
+        let tracker = self.monitor.phase(Phase::Assembly, plan.layout.cells.len());
+        for_each_row(
+            plan.layout.cells.iter().zip(plan.tiles.iter().copied()),
+            self.output_size.1.div_ceil(self.tile_height),
+            |cell, tile_idx| {
+                let output = cell.output_area(self.tile_width, self.tile_height);
+                let tile = self.render_cell(cell, tile_idx, output.width, output.height, plan.has_alpha);
+                tracker.advance();
+                tile
+            },
+            |row, cells| {
+                tracker.check()?;
+                visit(row, cells)
+            },
+        )
+    }
+
+    /// Generate the image mosaic one strip at a time, clipping each tile to
+    /// the shape of its cell.
+    fn render_shaped(&self, sink: &mut impl StripSink<C>) -> Result<(), TilrError> {
+        let plan = self.plan()?;
+        let tracker = self.monitor.phase(Phase::Assembly, plan.layout.cells.len());
+        let grid = self.tiles.grid();
+        let (tile_width, tile_height) = (self.tile_width, self.tile_height);
+        render_shaped_strips(
+            &plan.layout,
+            &plan.tiles,
+            self.output_size,
+            (tile_width, tile_height),
+            &self.tiling,
+            |cell, tile_idx, width, height| {
+                let tile = self.render_cell(cell, tile_idx, width, height, false);
+                tracker.advance();
+                tile
+            },
+            |x, y| match plan.has_alpha {
+                true => {
+                    let x = (x * grid.columns / tile_width).min(self.img.width() - 1);
+                    let y = (y * grid.rows / tile_height).min(self.img.height() - 1);
+                    alpha::<C>(self.img.get_pixel(x, y))
+                }
+                false => 1.0,
+            },
+            &mut |y: u32, strip: &RgbaBuffer<C>| {
+                tracker.check()?;
+                sink.write_strip(y, strip)
+            },
+        )
+    }
+
+    /// Get which tile was chosen for each cell of the mosaic, and how well
+    /// it matches.
+    ///
+    /// The map can be saved, and rendered again later with
+    /// [`PlacementMap::render`], e.g. at a higher resolution. Rendering this
+    /// mosaic afterwards reuses the same choices rather than matching the
+    /// cells again.
+    ///
+    /// # Errors
+    /// Fails if the mosaic is cancelled before its tiles are chosen.
+    pub fn placements(&self) -> Result<PlacementMap, TilrError> {
+        let plan = self.plan()?;
+        let placements = plan
+            .layout
+            .cells
+            .iter()
+            .zip(&plan.tiles)
+            .zip(&plan.distances)
+            .map(|((cell, &candidate), &distance)| {
+                let (tile, variant) = self.tiles.candidate(candidate);
+                Placement {
+                    column: cell.x,
+                    row: cell.y,
+                    span: cell.span,
+                    tile,
+                    variant,
+                    path: self.tiles.tiles()[tile].path().map(Path::to_path_buf),
+                    distance,
+                }
+            })
+            .collect();
+        let (columns, rows) = self.tiles.cells_in(&self.img);
+        Ok(PlacementMap {
+            columns,
+            rows,
+            shape: self.tiling.shape,
+            grout: self.tiling.grout.0,
+            grout_width: self.tiling.grout_width / self.tile_width.min(self.tile_height) as f32,
+            placements,
+        })
+    }
+
+    /// Lay out the cells of the mosaic and choose a tile for each one, the
+    /// first time this is called.
+    ///
+    /// If the mosaic is cancelled, nothing is kept, so a later call fails
+    /// too rather than seeing a partial plan.
+    fn plan(&self) -> Result<&Plan, TilrError> {
+        if let Some(plan) = self.plan.get() {
+            return Ok(plan);
+        }
+        let plan = self.make_plan()?;
+        Ok(self.plan.get_or_init(|| plan))
+    }
+
+    /// Lay out the cells of the mosaic and choose a tile for each one.
+    fn make_plan(&self) -> Result<Plan, TilrError> {
+        let (columns, rows) = self.tiles.cells_in(&self.img);
+        let grid = self.tiles.grid();
+        let mut layout = match (self.tiling.shape, &self.adaptive) {
+            (Shape::Square, Some(adaptive)) => {
+                Layout::adaptive(&self.img, columns, rows, grid, self.tiles.metric(), adaptive)
             }
+            (Shape::Square, None) => Layout::uniform(columns, rows),
+            (shape, _) => Layout::shaped(shape, columns, rows),
+        };
+        let has_alpha = self.img.pixels().any(|px| alpha::<C>(px) < 1.0);
+        if has_alpha {
+            // don't spend tiles on cells that won't be seen
+            let visible: Vec<bool> = layout
+                .cells
+                .iter()
+                .map(|cell| {
+                    let (target, area) = layout.target(&self.img, cell, grid);
+                    is_visible(&target, area)
+                })
+                .collect();
+            let mut visible = visible.into_iter();
+            layout.cells.retain(|_| visible.next().unwrap_or(false));
+        }
+        self.monitor.check()?;
+        let cells = layout.cells.len();
+        let tracker = self.monitor.phase(Phase::Signatures, cells);
+        let signatures = self.tiles.cell_signatures(&self.img, &layout, &tracker)?;
+        let tracker = self.monitor.phase(Phase::Matching, cells);
+        let mut tiles = self.selection.select(&self.tiles, &signatures, &layout, &tracker)?;
+        if let Some((previous, threshold)) = &self.previous {
+            // keep the tiles of the last frame where they still fit, so they don't flicker
+            for ((cell, signature), tile) in layout.cells.iter().zip(&signatures).zip(&mut tiles) {
+                let kept = previous.get(cell).copied();
+                if let Some(kept) = kept.filter(|&kept| self.tiles.sq_dist(signature, kept).sqrt() <= *threshold) {
+                    *tile = kept;
+                }
+            }
+        }
+        let distances = signatures
+            .iter()
+            .zip(&tiles)
+            .map(|(signature, &tile)| self.tiles.sq_dist(signature, tile).sqrt())
+            .collect();
+        Ok(Plan {
+            layout,
+            tiles,
+            distances,
+            has_alpha,
+        })
+    }
 
-            // Move to the next col in the mosaic
-            mos_x += tile_width;
+    /// Build the image placed in a cell of the mosaic, at the given size.
+    ///
+    /// With `mask`, the image is made transparent where the original image
+    /// is, which is only right for square cells drawn at their output area.
+    fn render_cell(&self, cell: &Cell, tile_idx: usize, width: u32, height: u32, mask: bool) -> Arc<RgbaBuffer<C>> {
+        let tile = self.tiles.tile_image(tile_idx, width, height);
+        let plan = self.plan.get().expect("cells should only be rendered once planned");
+        let (target, area) = plan.layout.target(&self.img, cell, self.tiles.grid());
+        let mut adjusted = match &self.color_adjust {
+            Some(adjust) => {
+                let target = target.view(area.x, area.y, area.width, area.height);
+                let target: Vec<_> = target.pixels().map(|(_, _, px)| px).collect();
+                adjust.apply(&tile, &target)
+            }
+            None => Cow::Borrowed(&*tile),
+        };
+        if mask {
+            mask_alpha(adjusted.to_mut(), &self.img, area);
+        }
+        match adjusted {
+            Cow::Borrowed(_) => tile,
+            Cow::Owned(adjusted) => Arc::new(adjusted),
         }
+    }
+}
+
+/// The cells of a mosaic and the tiles chosen for them.
+#[derive(Debug)]
+struct Plan {
+    /// The cells of the mosaic.
+    layout: Layout,
+    /// The index of the tile chosen for each cell.
+    tiles: Vec<usize>,
+    /// The distance between each cell and its tile.
+    distances: Vec<f32>,
+    /// Whether the original image has any transparency.
+    has_alpha: bool,
+}
 
-        mosaic.0
+/// Render cells, sorted by row, into a sink one strip at a time.
+///
+/// `render` builds the image placed in a cell, at the cell's output size.
+/// The cells starting in each row are rendered in parallel, and then the
+/// lines of the strip are filled in parallel.
+pub(crate) fn render_strips<'a, C: Channel>(
+    cells: impl Iterator<Item = (&'a Cell, usize)>,
+    (width, height): (u32, u32),
+    tile_size: (u32, u32),
+    render: impl Fn(&Cell, usize) -> Arc<RgbaBuffer<C>> + Sync,
+    sink: &mut impl StripSink<C>,
+) -> Result<(), TilrError> {
+    let tile_height = tile_size.1;
+    for_each_row(cells, height.div_ceil(tile_height), render, |row, cells| {
+        let y = row * tile_height;
+        let mut strip = RgbaBuffer::new(width, tile_height.min(height - y));
+        fill_strip(&mut strip, width, row, tile_size, cells);
+        sink.write_strip(y, &strip)
+    })
+}
+
+/// Render the given cells, in order of their rows, and pass the index of
+/// each of the given number of rows to `visit` along with the rendered
+/// cells that overlap it.
+///
+/// The cells starting in each row are rendered in parallel just before
+/// the row is visited; larger cells are kept until their last row.
+fn for_each_row<'a, C: Channel>(
+    cells: impl Iterator<Item = (&'a Cell, usize)>,
+    rows: u32,
+    render: impl Fn(&Cell, usize) -> Arc<RgbaBuffer<C>> + Sync,
+    mut visit: impl FnMut(u32, &[(&'a Cell, Arc<RgbaBuffer<C>>)]) -> Result<(), TilrError>,
+) -> Result<(), TilrError> {
+    let mut cells = cells.peekable();
+    let mut active: Vec<(&Cell, Arc<RgbaBuffer<C>>)> = Vec::new();
+    for row in 0..rows {
+        let starting: Vec<_> = std::iter::from_fn(|| cells.next_if(|(cell, _)| cell.y == row)).collect();
+        active.par_extend(
+            starting
+                .into_par_iter()
+                .map(|(cell, tile_idx)| (cell, render(cell, tile_idx))),
+        );
+        visit(row, &active)?;
+        active.retain(|(cell, _)| cell.y + cell.span > row + 1);
     }
+    Ok(())
 }
 
-/// A wrapper around a [`DynamicImage`] used to build the resulting
-/// image mosaic.
-struct Inner(RgbImage);
+/// Copy the part of each of the given rendered cells that overlaps the
+/// strip of the mosaic for the given row of cells, filling the lines of
+/// the strip in parallel.
+///
+/// The strip is given as the channels of its pixels, `width` pixels per
+/// line; cells in the last row and column may be cropped.
+fn fill_strip<C: Channel>(
+    strip: &mut [C],
+    width: u32,
+    row: u32,
+    (tile_width, tile_height): (u32, u32),
+    cells: &[(&Cell, Arc<RgbaBuffer<C>>)],
+) {
+    let line_len = width as usize * 4;
+    strip.par_chunks_mut(line_len).enumerate().for_each(|(line, pixels)| {
+        for (cell, tile) in cells {
+            let output = cell.output_area(tile_width, tile_height);
+            if output.x >= width {
+                continue;
+            }
+            let tile_line = (row - cell.y) * tile_height + line as u32;
+            let len = output.width.min(width - output.x) as usize * 4;
+            let start = tile_line as usize * tile.width() as usize * 4;
+            let x = output.x as usize * 4;
+            pixels[x..x + len].copy_from_slice(&tile.as_raw()[start..start + len]);
+        }
+    });
+}
 
-impl Inner {
-    /// Add a [`Tile`] to the image mosaic.
-    ///
-    /// More specifically, insert the pixels of a given [`Tile`] into
-    /// this image at an offset based on where that [`Tile`] belongs
-    /// in the [`Mosaic`].
-    pub fn add_tile(&mut self, tile: &Tile, start_coords: (u32, u32)) {
-        let (start_x, start_y) = start_coords;
-        self.0.copy_from(tile.img(), start_x, start_y).expect("tile should fit in mosaic");
+/// Multiply the alpha of a tile by the alpha of the area of the original
+/// image it covers, so transparent areas stay transparent.
+fn mask_alpha<C: Channel>(tile: &mut RgbaBuffer<C>, img: &RgbaBuffer<C>, target: Rect) {
+    let (width, height) = tile.dimensions();
+    for (x, y, px) in tile.enumerate_pixels_mut() {
+        let src = img.get_pixel(target.x + x * target.width / width, target.y + y * target.height / height);
+        scale_alpha::<C>(px, alpha::<C>(src));
     }
 }
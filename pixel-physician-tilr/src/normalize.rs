@@ -0,0 +1,225 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use image::imageops::FilterType;
+use image::{imageops, ColorType, DynamicImage, GenericImageView, Rgba};
+
+use crate::pixel::{convert, Channel, RgbaBuffer};
+
+/// How an image, such as a tile, is brought to a size with a different
+/// aspect ratio.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fit {
+    /// Crop the middle of the image to the requested aspect ratio, then
+    /// resize it.
+    #[default]
+    Crop,
+    /// Resize the whole image to fit within the requested size, and fill
+    /// the rest with a background color.
+    Letterbox {
+        /// The color of the bars around the image.
+        background: Rgba<u8>,
+    },
+    /// Resize the image to the requested size, ignoring its aspect ratio.
+    Stretch,
+}
+
+/// How the tiles of a [`TileSet`](crate::TileSet) are brought to a common
+/// size, since real photo folders mix sizes and orientations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalize {
+    /// The size to bring every tile to, or `None` to use the size of the
+    /// first tile.
+    pub size: Option<(u32, u32)>,
+    /// How to deal with tiles of a different aspect ratio.
+    pub fit: Fit,
+    /// The filter used whenever a tile is resized.
+    pub filter: FilterType,
+}
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Self {
+            size: None,
+            fit: Fit::default(),
+            filter: FilterType::Lanczos3,
+        }
+    }
+}
+
+impl Normalize {
+    /// Bring an image to the given size according to these options.
+    pub(crate) fn apply(&self, img: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        if img.dimensions() == (width, height) {
+            return img;
+        }
+
+        let (img_w, img_h) = (img.width() as u64, img.height() as u64);
+        match self.fit {
+            Fit::Crop => {
+                // the largest centered area with the requested aspect ratio
+                let (crop_w, crop_h) = if img_w * height as u64 > img_h * width as u64 {
+                    (img_h * width as u64 / height as u64, img_h)
+                } else {
+                    (img_w, img_w * height as u64 / width as u64)
+                };
+                let (crop_w, crop_h) = (crop_w.max(1) as u32, crop_h.max(1) as u32);
+                let x = (img.width() - crop_w) / 2;
+                let y = (img.height() - crop_h) / 2;
+                img.crop_imm(x, y, crop_w, crop_h).resize_exact(width, height, self.filter)
+            }
+            Fit::Letterbox { background } => {
+                let fitted = img.resize(width, height, self.filter);
+                // keep the bit depth of the image
+                match fitted.color() {
+                    ColorType::Rgb32F | ColorType::Rgba32F => letterbox::<f32>(fitted, width, height, background),
+                    c if c.bytes_per_pixel() > c.channel_count() => letterbox::<u16>(fitted, width, height, background),
+                    _ => letterbox::<u8>(fitted, width, height, background),
+                }
+            }
+            Fit::Stretch => img.resize_exact(width, height, self.filter),
+        }
+    }
+}
+
+/// Center an image on a canvas of the given size filled with a background
+/// color, with channels of type `C`.
+fn letterbox<C: Channel>(img: DynamicImage, width: u32, height: u32, background: Rgba<u8>) -> DynamicImage {
+    let fitted = C::from_dynamic(img);
+    let mut canvas = RgbaBuffer::from_pixel(width, height, convert::<C>(background));
+    let x = (width - fitted.width()) / 2;
+    let y = (height - fitted.height()) / 2;
+    imageops::overlay(&mut canvas, &fitted, x as i64, y as i64);
+    C::into_dynamic(canvas)
+}
+
+/// Rotate and flip an image according to its EXIF orientation, so that it
+/// is the right way up.
+pub(crate) fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, RgbaImage};
+
+    use super::*;
+
+    /// Build an image whose pixels record their own position.
+    fn positions(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 0, 255])))
+    }
+
+    fn normalize(fit: Fit) -> Normalize {
+        Normalize {
+            size: None,
+            fit,
+            filter: FilterType::Nearest,
+        }
+    }
+
+    #[test]
+    fn crop_keeps_the_middle() {
+        let crop = normalize(Fit::Crop);
+        // the middle 4×4 of a wide image
+        let img = crop.apply(positions(9, 4), 4, 4).into_rgba8();
+        assert_eq!(img.dimensions(), (4, 4));
+        assert_eq!(img.get_pixel(0, 0).0, [2, 0, 0, 255]);
+        assert_eq!(img.get_pixel(3, 3).0, [5, 3, 0, 255]);
+        // the middle 3×3 of a tall one
+        let img = crop.apply(positions(3, 7), 3, 3).into_rgba8();
+        assert_eq!(img.get_pixel(0, 0).0, [0, 2, 0, 255]);
+        assert_eq!(img.get_pixel(2, 2).0, [2, 4, 0, 255]);
+        // the middle 6×3 of an odd-sized image, then resized
+        let img = crop.apply(positions(7, 5), 4, 2).into_rgba8();
+        assert_eq!(img.dimensions(), (4, 2));
+        assert!(img.pixels().all(|px| px[1] >= 1 && px[1] <= 3));
+    }
+
+    #[test]
+    fn letterbox_centers_the_whole_image() {
+        let background = Rgba([0, 0, 255, 255]);
+        let letterbox = normalize(Fit::Letterbox { background });
+        let red = Rgba([255, 0, 0, 255]);
+        let img = letterbox.apply(DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 4, red)), 6, 6).into_rgba8();
+        assert_eq!(img.dimensions(), (6, 6));
+        for (x, y, px) in img.enumerate_pixels() {
+            let expected = if (1..4).contains(&y) { red } else { background };
+            assert_eq!(*px, expected, "at {x}, {y}");
+        }
+
+        // bars at the sides of a tall image, keeping its bit depth
+        let tall: ImageBuffer<Rgba<u16>, _> = ImageBuffer::from_pixel(3, 9, Rgba([65535, 0, 0, 65535]));
+        let img = letterbox.apply(DynamicImage::ImageRgba16(tall), 5, 6);
+        assert_eq!(img.color(), ColorType::Rgba16);
+        let img = img.into_rgba8();
+        let columns: Vec<bool> = (0..5).map(|x| *img.get_pixel(x, 3) == red).collect();
+        assert_eq!(columns, [false, true, true, false, false]);
+    }
+
+    #[test]
+    fn stretch_ignores_the_aspect_ratio() {
+        let img = normalize(Fit::Stretch).apply(positions(9, 4), 3, 8).into_rgba8();
+        assert_eq!(img.dimensions(), (3, 8));
+        // each pixel comes from the matching part of the image
+        for (x, y, px) in img.enumerate_pixels() {
+            assert!((x * 3..x * 3 + 3).contains(&(px[0] as u32)), "at {x}, {y}");
+            assert_eq!(px[1] as u32, y / 2, "at {x}, {y}");
+        }
+    }
+
+    #[test]
+    fn images_of_the_right_size_are_untouched() {
+        let img = normalize(Fit::Crop).apply(positions(5, 3), 5, 3);
+        assert_eq!(img, positions(5, 3));
+    }
+
+    #[test]
+    fn orientations_turn_images_the_right_way_up() {
+        const WIDTH: u32 = 3;
+        const HEIGHT: u32 = 2;
+        // where each pixel of the upright image is stored, for each orientation
+        let stored = |orientation, x, y| match orientation {
+            1 => (x, y),
+            2 => (WIDTH - 1 - x, y),
+            3 => (WIDTH - 1 - x, HEIGHT - 1 - y),
+            4 => (x, HEIGHT - 1 - y),
+            5 => (y, x),
+            6 => (y, HEIGHT - 1 - x),
+            7 => (WIDTH - 1 - y, HEIGHT - 1 - x),
+            _ => (WIDTH - 1 - y, x),
+        };
+        for orientation in 1..=8 {
+            let img = apply_orientation(positions(WIDTH, HEIGHT), orientation).into_rgba8();
+            let expected = if orientation >= 5 { (HEIGHT, WIDTH) } else { (WIDTH, HEIGHT) };
+            assert_eq!(img.dimensions(), expected, "orientation {orientation}");
+            for (x, y, px) in img.enumerate_pixels() {
+                let (sx, sy) = stored(orientation, x, y);
+                assert_eq!(px.0, [sx as u8, sy as u8, 0, 255], "orientation {orientation} at {x}, {y}");
+            }
+        }
+        // unknown orientations leave the image alone
+        assert_eq!(apply_orientation(positions(WIDTH, HEIGHT), 0), positions(WIDTH, HEIGHT));
+    }
+}
@@ -0,0 +1,123 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::any::Any;
+use std::io::Write;
+
+use image::{Pixel, RgbaImage};
+
+use crate::error::TilrError;
+use crate::pixel::{Channel, RgbaBuffer};
+
+/// A destination for a [`Mosaic`](crate::Mosaic) that is rendered one strip
+/// at a time, so that the whole mosaic never has to fit in memory.
+///
+/// See [`Mosaic::render_strips`](crate::Mosaic::render_strips). Closures
+/// taking the same arguments as [`StripSink::write_strip`] are sinks too.
+pub trait StripSink<C: Channel = u8> {
+    /// Receive the next strip of the mosaic, whose top row is row `y` of
+    /// the mosaic. Strips are as wide as the mosaic, and arrive in order
+    /// from top to bottom.
+    fn write_strip(&mut self, y: u32, strip: &RgbaBuffer<C>) -> Result<(), TilrError>;
+}
+
+impl<C, F> StripSink<C> for F
+where
+    C: Channel,
+    F: FnMut(u32, &RgbaBuffer<C>) -> Result<(), TilrError>,
+{
+    fn write_strip(&mut self, y: u32, strip: &RgbaBuffer<C>) -> Result<(), TilrError> {
+        self(y, strip)
+    }
+}
+
+/// A [`StripSink`] that encodes the mosaic as a PNG while it is rendered.
+///
+/// Mosaics of any [`Channel`] type can be written as either an 8-bit or a
+/// 16-bit PNG.
+#[allow(missing_debug_implementations)]
+pub struct PngSink<W: Write + 'static> {
+    /// The encoder the rows are written to.
+    writer: png::StreamWriter<'static, W>,
+    /// Whether to keep the alpha channel of the mosaic.
+    alpha: bool,
+    /// Whether the PNG has 16 bits per channel rather than 8.
+    sixteen_bit: bool,
+}
+
+impl<W: Write + 'static> PngSink<W> {
+    /// Start an 8-bit PNG of the given size, which should be the
+    /// [`Mosaic::output_size`](crate::Mosaic::output_size) of the mosaic
+    /// rendered into it.
+    ///
+    /// If `alpha` is `false`, the alpha channel of the mosaic is dropped.
+    ///
+    /// # Errors
+    /// Fails if the PNG header can't be written.
+    pub fn new(writer: W, width: u32, height: u32, alpha: bool) -> Result<Self, TilrError> {
+        Self::start(writer, width, height, alpha, false)
+    }
+
+    /// Start a 16-bit PNG of the given size, as with [`PngSink::new`].
+    ///
+    /// # Errors
+    /// Fails if the PNG header can't be written.
+    pub fn new_16bit(writer: W, width: u32, height: u32, alpha: bool) -> Result<Self, TilrError> {
+        Self::start(writer, width, height, alpha, true)
+    }
+
+    /// Write the PNG header.
+    fn start(writer: W, width: u32, height: u32, alpha: bool, sixteen_bit: bool) -> Result<Self, TilrError> {
+        let mut encoder = png::Encoder::new(writer, width, height);
+        encoder.set_color(if alpha { png::ColorType::Rgba } else { png::ColorType::Rgb });
+        encoder.set_depth(if sixteen_bit { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+        let writer = encoder.write_header()?.into_stream_writer()?;
+        Ok(Self { writer, alpha, sixteen_bit })
+    }
+
+    /// Finish the PNG once every strip has been written.
+    ///
+    /// # Errors
+    /// Fails if the image is incomplete or can't be written.
+    pub fn finish(self) -> Result<(), TilrError> {
+        Ok(self.writer.finish()?)
+    }
+}
+
+impl<W: Write + 'static, C: Channel> StripSink<C> for PngSink<W> {
+    fn write_strip(&mut self, _y: u32, strip: &RgbaBuffer<C>) -> Result<(), TilrError> {
+        let io_err = |e: std::io::Error| TilrError::Encode(e.into());
+        // 8-bit mosaics with alpha are already in the right layout
+        if let Some(strip) = (strip as &dyn Any).downcast_ref::<RgbaImage>() {
+            if self.alpha && !self.sixteen_bit {
+                return self.writer.write_all(strip.as_raw()).map_err(io_err);
+            }
+        }
+
+        let channels = if self.alpha { 4 } else { 3 };
+        let mut bytes = Vec::with_capacity(strip.len() / 4 * channels * if self.sixteen_bit { 2 } else { 1 });
+        for px in strip.pixels() {
+            for &c in &px.channels()[..channels] {
+                if self.sixteen_bit {
+                    // PNGs are big-endian
+                    bytes.extend(u16::from_unit(c.to_unit()).to_be_bytes());
+                } else {
+                    bytes.push(u8::from_unit(c.to_unit()));
+                }
+            }
+        }
+        self.writer.write_all(&bytes).map_err(io_err)
+    }
+}
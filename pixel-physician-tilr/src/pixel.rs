@@ -0,0 +1,160 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Debug;
+
+use image::{DynamicImage, ImageBuffer, Pixel, Primitive, Rgba};
+
+use crate::color::{decode_srgb, to_linear, Color, ColorMetric};
+
+/// An RGBA image whose channels are of type `C`.
+pub type RgbaBuffer<C> = ImageBuffer<<C as Channel>::Rgba, Vec<C>>;
+
+/// The type of the channels of the pixels that a [`Mosaic`](crate::Mosaic)
+/// is built from and rendered to: `u8`, `u16` or `f32`.
+///
+/// `u8` is the default everywhere. `u16` keeps the precision of 16-bit
+/// scans from the tiles through to the output, and `f32` also keeps values
+/// above `1.0` in HDR images. Averages and distances are computed in
+/// floating point either way.
+pub trait Channel: Primitive + Debug + Send + Sync + 'static {
+    /// The number of bins that histograms of channels of this type, such
+    /// as for [`ColorAdjustMode::HistogramMatch`](crate::ColorAdjustMode::HistogramMatch),
+    /// are divided into.
+    const LEVELS: usize;
+
+    /// An RGBA pixel with channels of this type, i.e. [`Rgba<Self>`].
+    ///
+    /// This is spelled out as an associated type because `image` doesn't
+    /// export every bound it puts on the channels of an [`Rgba`] pixel.
+    type Rgba: Pixel<Subpixel = Self> + Debug + Send + Sync + 'static;
+
+    /// Convert a value to the range `[0, 1]`, where `1` is the value of a
+    /// fully bright channel or an opaque alpha. Floating-point values may
+    /// lie outside this range.
+    fn to_unit(self) -> f64;
+
+    /// Convert a value in the range `[0, 1]` to this type, rounding and
+    /// clamping it if needed.
+    fn from_unit(value: f64) -> Self;
+
+    /// Convert an image to RGBA with channels of this type.
+    fn from_dynamic(img: DynamicImage) -> RgbaBuffer<Self>;
+
+    /// Wrap an image with channels of this type in a [`DynamicImage`].
+    fn into_dynamic(img: RgbaBuffer<Self>) -> DynamicImage;
+
+    /// Convert the color of a pixel into the color space of a metric.
+    fn to_space(px: &Self::Rgba, metric: ColorMetric) -> Color {
+        let [r, g, b] = [0, 1, 2].map(|c| px.channels()[c].to_unit() as f32);
+        metric.to_space_unit([r, g, b])
+    }
+
+    /// Decode the sRGB color of a pixel into linear light.
+    fn to_linear(px: &Self::Rgba) -> Color {
+        [0, 1, 2].map(|c| decode_srgb(px.channels()[c].to_unit() as f32))
+    }
+}
+
+impl Channel for u8 {
+    const LEVELS: usize = 256;
+
+    type Rgba = Rgba<u8>;
+
+    fn to_unit(self) -> f64 {
+        self as f64 / 255.0
+    }
+
+    fn from_unit(value: f64) -> Self {
+        (value * 255.0).round().clamp(0.0, 255.0) as u8
+    }
+
+    fn from_dynamic(img: DynamicImage) -> RgbaBuffer<Self> {
+        img.into_rgba8()
+    }
+
+    fn into_dynamic(img: RgbaBuffer<Self>) -> DynamicImage {
+        DynamicImage::ImageRgba8(img)
+    }
+
+    fn to_space(px: &Rgba<u8>, metric: ColorMetric) -> Color {
+        // 8-bit channels have a faster path through lookup tables
+        metric.to_space(&px.to_rgb())
+    }
+
+    fn to_linear(px: &Rgba<u8>) -> Color {
+        to_linear(&px.to_rgb())
+    }
+}
+
+impl Channel for u16 {
+    const LEVELS: usize = 4096;
+
+    type Rgba = Rgba<u16>;
+
+    fn to_unit(self) -> f64 {
+        self as f64 / 65535.0
+    }
+
+    fn from_unit(value: f64) -> Self {
+        (value * 65535.0).round().clamp(0.0, 65535.0) as u16
+    }
+
+    fn from_dynamic(img: DynamicImage) -> RgbaBuffer<Self> {
+        img.into_rgba16()
+    }
+
+    fn into_dynamic(img: RgbaBuffer<Self>) -> DynamicImage {
+        DynamicImage::ImageRgba16(img)
+    }
+}
+
+impl Channel for f32 {
+    const LEVELS: usize = 4096;
+
+    type Rgba = Rgba<f32>;
+
+    fn to_unit(self) -> f64 {
+        self as f64
+    }
+
+    fn from_unit(value: f64) -> Self {
+        value as f32
+    }
+
+    fn from_dynamic(img: DynamicImage) -> RgbaBuffer<Self> {
+        img.into_rgba32f()
+    }
+
+    fn into_dynamic(img: RgbaBuffer<Self>) -> DynamicImage {
+        DynamicImage::ImageRgba32F(img)
+    }
+}
+
+/// Convert an 8-bit color, such as a background or grout color, to
+/// channels of type `C`.
+pub(crate) fn convert<C: Channel>(px: Rgba<u8>) -> C::Rgba {
+    *C::Rgba::from_slice(&px.0.map(|c| C::from_unit(c.to_unit())))
+}
+
+/// Get the alpha of a pixel, in `[0, 1]`.
+pub(crate) fn alpha<C: Channel>(px: &C::Rgba) -> f64 {
+    px.channels()[3].to_unit()
+}
+
+/// Scale the alpha of a pixel by a factor in `[0, 1]`.
+pub(crate) fn scale_alpha<C: Channel>(px: &mut C::Rgba, factor: f64) {
+    px.channels_mut()[3] = C::from_unit(alpha::<C>(px) * factor);
+}
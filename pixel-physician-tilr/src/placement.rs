@@ -0,0 +1,399 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::TilrError;
+use crate::layout::{Cell, Layout};
+use crate::mosaic::render_strips;
+use crate::output::StripSink;
+use crate::pixel::{Channel, RgbaBuffer};
+use crate::shape::{render_shaped_strips, Shape, Tiling};
+use crate::tiles::TileSet;
+use crate::variant::Variant;
+
+/// Which tile was placed in each cell of a [`Mosaic`](crate::Mosaic); see
+/// [`Mosaic::placements`](crate::Mosaic::placements).
+///
+/// Maps can be saved as JSON and loaded again to render the same mosaic
+/// later, or saved as CSV, e.g. to credit the photos used in a print.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlacementMap {
+    /// The number of base cells across the mosaic.
+    pub columns: u32,
+    /// The number of base cells down the mosaic.
+    pub rows: u32,
+    /// The shape of the cells; see [`Tiling::shape`].
+    #[serde(default)]
+    pub shape: Shape,
+    /// The RGBA color of the grout between cells; see [`Tiling::grout`].
+    #[serde(default)]
+    pub grout: [u8; 4],
+    /// The width of the grout between cells, as a fraction of the shorter
+    /// side of a tile, so that it scales with the tiles; see
+    /// [`Tiling::grout_width`].
+    #[serde(default)]
+    pub grout_width: f32,
+    /// The tile placed in each cell, in row-major order. Cells that were
+    /// left empty, because the original image is transparent there, are
+    /// missing.
+    pub placements: Vec<Placement>,
+}
+
+/// The tile placed in a single cell of a mosaic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Placement {
+    /// The column of the top-left base cell covered by the cell, or for
+    /// cells of other shapes than [`Shape::Square`], the column of the cell
+    /// among cells of that shape.
+    pub column: u32,
+    /// The row of the top-left base cell covered by the cell, or of the
+    /// cell among cells of its shape.
+    pub row: u32,
+    /// The number of base cells the cell spans, both across and down; this
+    /// is only larger than `1` for [`Adaptive`](crate::Adaptive) mosaics.
+    pub span: u32,
+    /// The index of the tile in its [`TileSet`].
+    pub tile: usize,
+    /// The rotation or mirror image of the tile that was placed.
+    #[serde(default)]
+    pub variant: Variant,
+    /// The file the tile was loaded from, if known.
+    pub path: Option<PathBuf>,
+    /// The distance between the colors of the cell and of the tile, in the
+    /// units of the [`ColorMetric`](crate::ColorMetric)'s color space,
+    /// including any difference in [`Structure`](crate::Structure).
+    pub distance: f32,
+}
+
+impl PlacementMap {
+    /// Write the map as JSON.
+    ///
+    /// # Errors
+    /// Fails if the writer fails.
+    pub fn write_json(&self, mut writer: impl Write) -> Result<(), TilrError> {
+        serde_json::to_writer_pretty(&mut writer, self).map_err(|e| TilrError::Map(e.into()))?;
+        writer.flush().map_err(TilrError::Map)
+    }
+
+    /// Read a map written by [`PlacementMap::write_json`].
+    ///
+    /// # Errors
+    /// Fails if the reader fails or doesn't hold a valid map.
+    pub fn read_json(reader: impl Read) -> Result<Self, TilrError> {
+        serde_json::from_reader(reader).map_err(|e| TilrError::Map(e.into()))
+    }
+
+    /// Write the map as CSV, with one row per cell and a header row.
+    ///
+    /// # Errors
+    /// Fails if the writer fails.
+    pub fn write_csv(&self, mut writer: impl Write) -> Result<(), TilrError> {
+        let mut write = || -> io::Result<()> {
+            writeln!(writer, "column,row,span,tile,variant,path,distance")?;
+            for p in &self.placements {
+                let path = p.path.as_deref().map(|path| path.to_string_lossy()).unwrap_or_default();
+                let path = if path.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", path.replace('"', "\"\""))
+                } else {
+                    path.into_owned()
+                };
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{},{}",
+                    p.column,
+                    p.row,
+                    p.span,
+                    p.tile,
+                    p.variant.name(),
+                    path,
+                    p.distance
+                )?;
+            }
+            writer.flush()
+        };
+        write().map_err(TilrError::Map)
+    }
+
+    /// Get the width and height, in pixels, of the mosaic rendered from
+    /// this map with tiles of the given size.
+    ///
+    /// # Errors
+    /// Fails if the mosaic would be too large to address.
+    pub fn output_size(&self, tile_width: u32, tile_height: u32) -> Result<(u32, u32), TilrError> {
+        match (self.columns.checked_mul(tile_width), self.rows.checked_mul(tile_height)) {
+            (Some(width), Some(height)) => Ok((width, height)),
+            _ => Err(invalid_map(format!(
+                "{}×{} cells of {tile_width}×{tile_height} pixels are too large a mosaic",
+                self.columns, self.rows
+            ))),
+        }
+    }
+
+    /// Render the mosaic described by this map with the given tiles, which
+    /// may be at any resolution and of any [`Channel`] type.
+    ///
+    /// Tiles are found by their path if they have one, and by their index
+    /// otherwise. Color adjustment and transparency from the original image
+    /// aren't applied again, since they need the original image. The grout
+    /// is scaled along with the tiles.
+    ///
+    /// # Errors
+    /// Fails if the tile size has no pixels, if the mosaic would be too
+    /// large, or if a tile isn't in the set.
+    pub fn render<C: Channel>(
+        &self,
+        tiles: &TileSet<C>,
+        tile_width: u32,
+        tile_height: u32,
+    ) -> Result<RgbaBuffer<C>, TilrError> {
+        let (width, height) = self.output_size(tile_width, tile_height)?;
+        let mut mosaic = RgbaBuffer::new(width, height);
+        self.render_strips(tiles, tile_width, tile_height, &mut |y: u32, strip: &RgbaBuffer<C>| {
+            image::imageops::replace(&mut mosaic, strip, 0, y as i64);
+            Ok(())
+        })?;
+        Ok(mosaic)
+    }
+
+    /// Render the mosaic described by this map one row of cells at a time,
+    /// passing each strip of the mosaic to the given sink, as with
+    /// [`Mosaic::render_strips`](crate::Mosaic::render_strips).
+    ///
+    /// # Errors
+    /// Fails if the tile size has no pixels, if the mosaic would be too
+    /// large, if a tile isn't in the set, or if the sink fails.
+    pub fn render_strips<C: Channel>(
+        &self,
+        tiles: &TileSet<C>,
+        tile_width: u32,
+        tile_height: u32,
+        sink: &mut impl StripSink<C>,
+    ) -> Result<(), TilrError> {
+        if tile_width == 0 || tile_height == 0 {
+            return Err(TilrError::ZeroSize("tile size"));
+        }
+        let output_size = self.output_size(tile_width, tile_height)?;
+        let mut cells = self.resolve(tiles)?;
+        cells.sort_by_key(|(cell, ..)| (cell.y, cell.x));
+        // cells refer to their tiles by their position in the sorted list
+        let image = |idx: usize, width, height| {
+            let (_, tile, variant) = cells[idx];
+            tiles.variant_image(tile, variant, width, height)
+        };
+
+        let tiling = self.tiling(tile_width, tile_height);
+        if !tiling.is_plain() {
+            let (columns, rows) = self.shape.index_size(self.columns, self.rows);
+            let layout = Layout {
+                shape: self.shape,
+                columns,
+                rows,
+                cells: cells.iter().map(|(cell, ..)| *cell).collect(),
+            };
+            let positions: Vec<usize> = (0..cells.len()).collect();
+            return render_shaped_strips(
+                &layout,
+                &positions,
+                output_size,
+                (tile_width, tile_height),
+                &tiling,
+                |_, idx, width, height| image(idx, width, height),
+                |_, _| 1.0,
+                sink,
+            );
+        }
+        render_strips(
+            cells.iter().enumerate().map(|(idx, (cell, ..))| (cell, idx)),
+            output_size,
+            (tile_width, tile_height),
+            |cell, idx| {
+                let output = cell.output_area(tile_width, tile_height);
+                image(idx, output.width, output.height)
+            },
+            sink,
+        )
+    }
+
+    /// Get the tiling the map was made with, with the grout scaled to tiles
+    /// of the given size.
+    fn tiling(&self, tile_width: u32, tile_height: u32) -> Tiling {
+        Tiling {
+            shape: self.shape,
+            grout: image::Rgba(self.grout),
+            grout_width: self.grout_width * tile_width.min(tile_height) as f32,
+        }
+    }
+
+    /// Find the cell, and the index of the tile in the given set and its
+    /// variant, for each placement.
+    fn resolve<C: Channel>(&self, tiles: &TileSet<C>) -> Result<Vec<(Cell, usize, Variant)>, TilrError> {
+        let by_path: HashMap<&Path, usize> = tiles
+            .tiles()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, tile)| Some((tile.path()?, idx)))
+            .collect();
+
+        self.placements
+            .iter()
+            .map(|p| {
+                let tile = match &p.path {
+                    // a set without paths can only be matched by index
+                    Some(path) if !by_path.is_empty() => by_path.get(path.as_path()).copied(),
+                    _ => Some(p.tile).filter(|&idx| idx < tiles.len()),
+                };
+                let tile = tile.ok_or_else(|| TilrError::MissingTile {
+                    tile: p.tile,
+                    path: p.path.clone(),
+                })?;
+                let (columns, rows) = self.shape.index_size(self.columns, self.rows);
+                let fits = p.span > 0
+                    && p.column.checked_add(p.span).is_some_and(|end| end <= columns)
+                    && p.row.checked_add(p.span).is_some_and(|end| end <= rows);
+                if !fits {
+                    return Err(invalid_map(format!("cell at {}, {} is outside the map", p.column, p.row)));
+                }
+                let cell = Cell {
+                    x: p.column,
+                    y: p.row,
+                    span: p.span,
+                };
+                Ok((cell, tile, p.variant))
+            })
+            .collect()
+    }
+}
+
+/// Report a map that can't be valid.
+fn invalid_map(message: String) -> TilrError {
+    TilrError::Map(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+    use crate::tiles::TileSetOptions;
+
+    fn placement(column: u32, row: u32, span: u32, path: Option<&str>) -> Placement {
+        Placement {
+            column,
+            row,
+            span,
+            tile: 0,
+            variant: Variant::Original,
+            path: path.map(PathBuf::from),
+            distance: 1.5,
+        }
+    }
+
+    fn map(columns: u32, rows: u32, placements: Vec<Placement>) -> PlacementMap {
+        PlacementMap {
+            columns,
+            rows,
+            shape: Shape::Square,
+            grout: [0; 4],
+            grout_width: 0.0,
+            placements,
+        }
+    }
+
+    fn tiles() -> TileSet {
+        let img = RgbaImage::from_pixel(2, 2, image::Rgba([200, 100, 50, 255]));
+        TileSet::new(vec![DynamicImage::ImageRgba8(img)], TileSetOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let mut original = map(4, 3, vec![placement(0, 0, 2, Some("tiles/a, b.png")), placement(2, 0, 1, None)]);
+        original.shape = Shape::Hexagon;
+        original.grout = [10, 20, 30, 255];
+        original.grout_width = 0.125;
+        original.placements[1].variant = Variant::Rotate270;
+        original.placements[1].tile = 7;
+
+        let mut json = Vec::new();
+        original.write_json(&mut json).unwrap();
+        assert_eq!(PlacementMap::read_json(json.as_slice()).unwrap(), original);
+    }
+
+    #[test]
+    fn json_without_tiling_reads_as_square() {
+        let json = r#"{"columns": 1, "rows": 1, "placements": []}"#;
+        let map = PlacementMap::read_json(json.as_bytes()).unwrap();
+        assert_eq!(map.shape, Shape::Square);
+        assert_eq!(map.grout_width, 0.0);
+    }
+
+    #[test]
+    fn csv_quotes_paths_that_need_it() {
+        let placements = vec![
+            placement(0, 0, 1, Some("plain.png")),
+            placement(1, 0, 1, Some("a,b.png")),
+            placement(2, 0, 1, Some("say \"hi\".png")),
+            placement(3, 0, 1, None),
+        ];
+        let mut csv = Vec::new();
+        map(4, 1, placements).write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "column,row,span,tile,variant,path,distance",
+                "0,0,1,0,original,plain.png,1.5",
+                "1,0,1,0,original,\"a,b.png\",1.5",
+                "2,0,1,0,original,\"say \"\"hi\"\".png\",1.5",
+                "3,0,1,0,original,,1.5",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_mosaics() {
+        let map = map(u32::MAX / 2, 2, vec![placement(0, 0, 1, None)]);
+        assert!(matches!(map.output_size(3, 1), Err(TilrError::Map(_))));
+        assert!(matches!(map.render(&tiles(), 3, 1), Err(TilrError::Map(_))));
+        assert_eq!(map.output_size(2, 1).unwrap(), (u32::MAX - 1, 2));
+    }
+
+    #[test]
+    fn rejects_cells_outside_the_map() {
+        let tiles = tiles();
+        for cell in [placement(4, 0, 1, None), placement(3, 2, 2, None), placement(0, 0, 0, None)] {
+            let map = map(4, 3, vec![cell]);
+            assert!(matches!(map.render(&tiles, 2, 2), Err(TilrError::Map(_))));
+        }
+        let map = map(4, 3, vec![placement(u32::MAX, 0, 2, None)]);
+        assert!(matches!(map.render(&tiles, 2, 2), Err(TilrError::Map(_))));
+    }
+
+    #[test]
+    fn renders_tiles_by_index() {
+        let map = map(2, 1, vec![placement(1, 0, 1, None)]);
+        let mosaic = map.render(&tiles(), 2, 2).unwrap();
+        assert_eq!(mosaic.dimensions(), (4, 2));
+        assert_eq!(mosaic.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(mosaic.get_pixel(3, 1).0, [200, 100, 50, 255]);
+        let missing = PlacementMap { placements: vec![Placement { tile: 1, ..placement(0, 0, 1, None) }], ..map };
+        assert!(matches!(missing.render(&tiles(), 2, 2), Err(TilrError::MissingTile { tile: 1, .. })));
+    }
+}
@@ -0,0 +1,180 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::error::TilrError;
+
+/// The most reports made for each [`Phase`] of a job, so that progress
+/// callbacks don't slow down large mosaics.
+const MAX_REPORTS: usize = 100;
+
+/// A stage in building a [`Mosaic`](crate::Mosaic).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Computing the signature of each cell of the original image.
+    Signatures,
+    /// Choosing a tile for each cell.
+    Matching,
+    /// Rendering the chosen tiles into the mosaic.
+    Assembly,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Phase::Signatures => "computing signatures",
+            Phase::Matching => "matching tiles",
+            Phase::Assembly => "rendering",
+        })
+    }
+}
+
+/// How far a [`Mosaic`](crate::Mosaic) has got in one [`Phase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The phase the mosaic is in.
+    pub phase: Phase,
+    /// The number of cells done in this phase.
+    pub done: usize,
+    /// The number of cells in this phase.
+    pub total: usize,
+}
+
+/// A flag for abandoning the work on a [`Mosaic`](crate::Mosaic) from
+/// another thread, e.g. when its result is no longer wanted.
+///
+/// Clones share the same flag. The mosaic checks it between cells, so
+/// work stops soon after [`CancelToken::cancel`] is called, with
+/// [`TilrError::Cancelled`].
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a token that hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask any work watching this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether [`CancelToken::cancel`] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A callback told of the [`Progress`] of a [`Mosaic`](crate::Mosaic).
+type Report = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Where a job reports its progress, and how it is cancelled.
+#[derive(Clone, Default)]
+pub(crate) struct Monitor {
+    /// The callback for progress, if any.
+    report: Option<Report>,
+    /// The token for cancelling the job, if any.
+    cancel: Option<CancelToken>,
+}
+
+impl Monitor {
+    /// Report progress to the given callback.
+    pub(crate) fn with_report(mut self, report: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.report = Some(Arc::new(report));
+        self
+    }
+
+    /// Stop the job once the given token is cancelled.
+    pub(crate) fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Fail if the job has been cancelled.
+    pub(crate) fn check(&self) -> Result<(), TilrError> {
+        match self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            true => Err(TilrError::Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    /// Start a phase of the job, covering `total` cells.
+    pub(crate) fn phase(&self, phase: Phase, total: usize) -> Tracker<'_> {
+        let tracker = Tracker {
+            monitor: self,
+            phase,
+            total,
+            every: total.div_ceil(MAX_REPORTS).max(1),
+            done: AtomicUsize::new(0),
+            reported: Mutex::new(None),
+        };
+        tracker.report(0);
+        tracker
+    }
+}
+
+/// The progress of one phase of a job, which may be shared between the
+/// threads doing it.
+pub(crate) struct Tracker<'a> {
+    /// The job this phase is part of.
+    monitor: &'a Monitor,
+    /// The phase being tracked.
+    phase: Phase,
+    /// The number of cells in the phase.
+    total: usize,
+    /// How many cells are done between reports.
+    every: usize,
+    /// The number of cells done so far.
+    done: AtomicUsize,
+    /// The number of cells done at the last report, so that reports from
+    /// different threads never go backwards.
+    reported: Mutex<Option<usize>>,
+}
+
+impl Tracker<'_> {
+    /// Fail if the job has been cancelled.
+    pub(crate) fn check(&self) -> Result<(), TilrError> {
+        self.monitor.check()
+    }
+
+    /// Count a cell as done, without checking for cancellation.
+    pub(crate) fn advance(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done.is_multiple_of(self.every) || done == self.total {
+            self.report(done);
+        }
+    }
+
+    /// Tell the callback, if any, how many cells are done.
+    fn report(&self, done: usize) {
+        let Some(report) = &self.monitor.report else {
+            return;
+        };
+        let mut reported = self.reported.lock().expect("progress lock poisoned");
+        if reported.is_some_and(|reported| reported >= done) {
+            return;
+        }
+        *reported = Some(done);
+        report(Progress {
+            phase: self.phase,
+            done,
+            total: self.total,
+        });
+    }
+}
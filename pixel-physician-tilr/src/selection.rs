@@ -0,0 +1,396 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::error::TilrError;
+use crate::layout::{Cell, Layout};
+use crate::pixel::Channel;
+use crate::progress::Tracker;
+use crate::tiles::TileSet;
+
+/// Options controlling how [`Tile`](crate::Tile)s are chosen for the cells
+/// of a [`Mosaic`](crate::Mosaic).
+///
+/// By default each cell simply gets its closest tile, which turns large flat
+/// areas of the original image into a wall of the same picture.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Selection {
+    /// The most times any one tile may be used, or `None` for no limit.
+    /// Every [`Variant`](crate::Variant) of a tile counts as a use of it.
+    pub max_uses: Option<usize>,
+    /// A tile may not be used in a cell within this many cells (across,
+    /// down or diagonally) of another cell using the same tile, in any
+    /// [`Variant`](crate::Variant).
+    pub repeat_radius: u32,
+    /// Pick at random among this many of the closest allowed tiles, rather
+    /// than always using the closest one.
+    pub top_k: usize,
+    /// The seed for random choices, so that a mosaic can be reproduced.
+    pub seed: u64,
+    /// How color error is spread between cells.
+    pub dither: Dither,
+}
+
+/// Dithering modes for the tile selection of a [`Mosaic`](crate::Mosaic).
+///
+/// Without dithering, each cell is matched on its own, so a smooth gradient
+/// in the original image turns into bands of the same tile. Dithering breaks
+/// these bands up, which matters most for small tile sets.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Dither {
+    /// Match every cell on its own.
+    #[default]
+    None,
+    /// Floyd–Steinberg error diffusion: the color error left after choosing
+    /// the tile for a cell is spread to the neighbouring cells that have not
+    /// been matched yet. Cells of an [`Adaptive`](crate::Adaptive) mosaic
+    /// spread their error along their whole right and bottom edges.
+    ///
+    /// Cells are matched in row-major order, so this disables the random
+    /// visiting order normally used with [`Selection::max_uses`].
+    FloydSteinberg,
+    /// Ordered dithering: each cell's color is offset by a 4×4 Bayer
+    /// threshold pattern before matching.
+    Ordered {
+        /// The size of the offsets, relative to the average distance
+        /// between the colors of neighbouring tiles. `1.0` is a good start.
+        spread: f32,
+    },
+}
+
+/// The 4×4 Bayer threshold matrix used for [`Dither::Ordered`].
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            max_uses: None,
+            repeat_radius: 0,
+            top_k: 1,
+            seed: 0,
+            dither: Dither::None,
+        }
+    }
+}
+
+impl Selection {
+    /// Check whether the choice for one cell depends on the choices for others.
+    fn is_constrained(&self) -> bool {
+        self.max_uses.is_some() || self.repeat_radius > 0
+    }
+
+    /// Choose a tile for each cell of the given layout, given the signature
+    /// of each cell.
+    ///
+    /// Returns the index of the chosen candidate for each cell; see
+    /// [`TileSet::candidate`]. If the constraints
+    /// can't be met for a cell, e.g. because every tile has been used up, that
+    /// cell gets one of its closest tiles regardless.
+    ///
+    /// Fails if the job tracked by `tracker` is cancelled.
+    pub(crate) fn select<C: Channel>(
+        &self,
+        tiles: &TileSet<C>,
+        signatures: &[Vec<f32>],
+        layout: &Layout,
+        tracker: &Tracker<'_>,
+    ) -> Result<Vec<usize>, TilrError> {
+        let k = self.top_k.max(1);
+        let ordered = match self.dither {
+            Dither::Ordered { spread } => Some(OrderedDither::new(tiles, spread)),
+            _ => None,
+        };
+        let dithered = |cell: &Cell, sig: &[f32]| match &ordered {
+            Some(ordered) => ordered.apply(sig, cell.x as usize, cell.y as usize),
+            None => sig.to_vec(),
+        };
+
+        if !self.is_constrained() && self.dither != Dither::FloydSteinberg {
+            // every cell is independent
+            return signatures
+                .par_iter()
+                .zip(&layout.cells)
+                .enumerate()
+                .map(|(i, (sig, cell))| {
+                    tracker.check()?;
+                    let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(i as u64));
+                    let chosen = pick(&tiles.closest(&dithered(cell, sig), k, |_| true), &mut rng);
+                    tracker.advance();
+                    Ok(chosen)
+                })
+                .collect();
+        }
+
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut order: Vec<usize> = (0..signatures.len()).collect();
+        if self.dither != Dither::FloydSteinberg {
+            // Visit the cells in a random order so that, when tiles run out, the
+            // leftovers aren't all bunched up at the bottom of the mosaic.
+            order.shuffle(&mut rng);
+        }
+
+        // Both the chosen tiles and the color error are tracked per base cell,
+        // so that cells of different sizes can see each other.
+        let (columns, rows) = (layout.columns as usize, layout.rows as usize);
+        let base_idx = |x: usize, y: usize| y * columns + x;
+        let radius = self.repeat_radius as usize;
+        let bounds = tiles.signature_bounds();
+        let mut errors = vec![vec![0.0; bounds.len()]; columns * rows];
+        let mut owners = vec![None; columns * rows];
+        let mut uses = vec![0; tiles.len()];
+        let mut chosen = vec![None; signatures.len()];
+
+        for i in order {
+            tracker.check()?;
+            let cell = &layout.cells[i];
+            let (x, y, span) = (cell.x as usize, cell.y as usize, cell.span as usize);
+            let footprint = || (y..y + span).flat_map(move |by| (x..x + span).map(move |bx| base_idx(bx, by)));
+
+            let mut nearby: Vec<usize> = (y.saturating_sub(radius)..(y + span + radius).min(rows))
+                .flat_map(|by| (x.saturating_sub(radius)..(x + span + radius).min(columns)).map(move |bx| base_idx(bx, by)))
+                .filter_map(|b| owners[b])
+                .collect();
+            nearby.sort_unstable();
+            nearby.dedup();
+
+            // Add the error carried over from earlier cells, keeping the result
+            // within the range of the tiles so the error can't run away.
+            let mut sig = dithered(cell, &signatures[i]);
+            let area = (span * span) as f32;
+            for (c, (s, (min, max))) in sig.iter_mut().zip(&bounds).enumerate() {
+                let error: f32 = footprint().map(|b| errors[b][c]).sum();
+                *s = (*s + error / area).clamp(*min, *max);
+            }
+
+            let allowed = |c: usize| {
+                let t = tiles.candidate(c).0;
+                self.max_uses.is_none_or(|max| uses[t] < max) && nearby.binary_search(&t).is_err()
+            };
+            let mut candidates = tiles.closest(&sig, k, allowed);
+            if candidates.is_empty() {
+                candidates = tiles.closest(&sig, k, |_| true);
+            }
+
+            let candidate = pick(&candidates, &mut rng);
+            let tile = tiles.candidate(candidate).0;
+            uses[tile] += 1;
+            chosen[i] = Some(candidate);
+            for b in footprint() {
+                owners[b] = Some(tile);
+            }
+
+            if self.dither == Dither::FloydSteinberg {
+                let error: Vec<f32> = sig.iter().zip(tiles.signature(candidate)).map(|(s, t)| s - t).collect();
+                // the base cells to the right, below-left, below and below-right
+                let right = (y..y + span).map(|by| (x + span, by, 7.0 / 16.0));
+                let below = (x..x + span).map(|bx| (bx, y + span, 5.0 / 16.0));
+                let corners = [(x.wrapping_sub(1), y + span, 3.0 / 16.0), (x + span, y + span, 1.0 / 16.0)];
+                for (bx, by, weight) in right.chain(below).chain(corners) {
+                    if bx >= columns || by >= rows {
+                        continue;
+                    }
+                    for (e, d) in errors[base_idx(bx, by)].iter_mut().zip(&error) {
+                        *e += weight * d;
+                    }
+                }
+            }
+            tracker.advance();
+        }
+
+        Ok(chosen.into_iter().map(|t| t.expect("every cell should be visited")).collect())
+    }
+}
+
+/// The precomputed state for [`Dither::Ordered`].
+#[derive(Debug)]
+struct OrderedDither {
+    /// The size of the largest offset, in the color space of the tile set.
+    scale: f32,
+    /// Which channels of each color are offset.
+    channels: [f32; 3],
+    /// The number of color components in each signature; any structure
+    /// after them is left alone.
+    color_dims: usize,
+}
+
+impl OrderedDither {
+    fn new<C: Channel>(tiles: &TileSet<C>, spread: f32) -> Self {
+        Self {
+            scale: spread * tiles.palette_spacing(),
+            channels: tiles.metric().dither_channels(),
+            color_dims: tiles.grid().dims(),
+        }
+    }
+
+    /// Offset a cell's signature by the threshold for the cell at (`x`, `y`).
+    fn apply(&self, sig: &[f32], x: usize, y: usize) -> Vec<f32> {
+        // center the thresholds around zero
+        let threshold = (BAYER_4X4[y % 4][x % 4] as f32 + 0.5) / 16.0 - 0.5;
+        sig.iter()
+            .enumerate()
+            .map(|(i, s)| match i < self.color_dims {
+                true => s + threshold * self.scale * self.channels[i % 3],
+                false => *s,
+            })
+            .collect()
+    }
+}
+
+/// Pick one of the candidate tiles at random.
+fn pick(candidates: &[(usize, f32)], rng: &mut impl Rng) -> usize {
+    candidates.choose(rng).expect("should have at least one tile").0
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+    use crate::progress::{Monitor, Phase};
+
+    /// Build a tile set of flat gray tiles.
+    fn grays(values: &[u8]) -> TileSet {
+        let imgs: Vec<DynamicImage> = values
+            .iter()
+            .map(|&v| DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([v, v, v, 255]))))
+            .collect();
+        TileSet::try_from(imgs).unwrap()
+    }
+
+    /// Get the signature of a flat gray cell.
+    fn gray(value: u8) -> Vec<f32> {
+        grays(&[value]).signature(0).to_vec()
+    }
+
+    /// Choose tiles for a uniform layout, returning the index of the tile
+    /// chosen for each cell.
+    fn select(selection: Selection, tiles: &TileSet, signatures: &[Vec<f32>], columns: u32) -> Vec<usize> {
+        let layout = Layout::uniform(columns, signatures.len() as u32 / columns);
+        let monitor = Monitor::default();
+        let tracker = monitor.phase(Phase::Matching, signatures.len());
+        let chosen = selection.select(tiles, signatures, &layout, &tracker).unwrap();
+        chosen.into_iter().map(|c| tiles.candidate(c).0).collect()
+    }
+
+    fn uses(chosen: &[usize], tiles: usize) -> Vec<usize> {
+        (0..tiles).map(|t| chosen.iter().filter(|&&c| c == t).count()).collect()
+    }
+
+    #[test]
+    fn max_uses_spreads_tiles_out() {
+        let tiles = grays(&[0, 60, 120, 180]);
+        let signatures = vec![gray(0); 8];
+        let selection = Selection {
+            max_uses: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(uses(&select(selection, &tiles, &signatures, 4), 4), [2, 2, 2, 2]);
+
+        // once every tile is used up, cells get their closest tile anyway
+        let selection = Selection {
+            max_uses: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(uses(&select(selection, &tiles, &signatures, 4), 4), [5, 1, 1, 1]);
+    }
+
+    #[test]
+    fn repeat_radius_keeps_tiles_apart() {
+        // enough tiles that the constraint never has to be broken
+        let values: Vec<u8> = (0..25).map(|v| v * 10).collect();
+        let tiles = grays(&values);
+        let (columns, rows) = (6, 5);
+        let signatures = vec![gray(0); columns * rows];
+        for radius in [1, 2] {
+            let selection = Selection {
+                repeat_radius: radius,
+                ..Default::default()
+            };
+            let chosen = select(selection, &tiles, &signatures, columns as u32);
+            for (a, &tile_a) in chosen.iter().enumerate() {
+                for (b, &tile_b) in chosen.iter().enumerate().skip(a + 1) {
+                    let dx = (a % columns).abs_diff(b % columns);
+                    let dy = (a / columns).abs_diff(b / columns);
+                    if dx.max(dy) <= radius as usize {
+                        assert_ne!(tile_a, tile_b, "cells {a} and {b} are within {radius}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn top_k_is_random_but_seeded() {
+        let tiles = grays(&[0, 10, 20, 200, 220]);
+        let signatures = vec![gray(5); 24];
+        for max_uses in [None, Some(24)] {
+            let selection = |seed| Selection {
+                max_uses,
+                top_k: 3,
+                seed,
+                ..Default::default()
+            };
+            let chosen = select(selection(7), &tiles, &signatures, 6);
+            assert!(chosen.iter().all(|&t| t < 3), "{chosen:?}");
+            assert!(uses(&chosen, 3).iter().all(|&n| n > 0), "{chosen:?}");
+            assert_eq!(select(selection(7), &tiles, &signatures, 6), chosen);
+            assert_ne!(select(selection(8), &tiles, &signatures, 6), chosen);
+        }
+    }
+
+    #[test]
+    fn floyd_steinberg_error_is_clamped_to_the_tiles() {
+        // Cells far darker than any tile carry no error past the darkest
+        // tile, so the bright cell at the end still gets the bright tile.
+        let tiles = grays(&[100, 150]);
+        let mut signatures = vec![gray(0); 11];
+        signatures.push(gray(150));
+        let selection = Selection {
+            dither: Dither::FloydSteinberg,
+            ..Default::default()
+        };
+        let chosen = select(selection, &tiles, &signatures, 12);
+        assert_eq!(chosen[..11], [0; 11]);
+        assert_eq!(chosen[11], 1);
+
+        // a gray between the tiles is made up of both
+        let signatures = vec![gray(125); 16];
+        assert_eq!(uses(&select(selection, &tiles, &signatures, 4), 2), [8, 8]);
+    }
+
+    #[test]
+    fn ordered_dither_breaks_up_flat_areas() {
+        let tiles = grays(&[100, 150]);
+        let signatures = vec![gray(125); 16];
+        let plain = select(Selection::default(), &tiles, &signatures, 4);
+        assert!(plain.iter().all(|&t| t == plain[0]));
+
+        for max_uses in [None, Some(16)] {
+            // offsets far larger than the tiles are apart are clamped to them
+            for spread in [1.0, 100.0] {
+                let selection = Selection {
+                    max_uses,
+                    dither: Dither::Ordered { spread },
+                    ..Default::default()
+                };
+                assert_eq!(uses(&select(selection, &tiles, &signatures, 4), 2), [8, 8]);
+            }
+        }
+    }
+}
@@ -0,0 +1,455 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use image::{Pixel, Rgba};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelExtend, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use serde::{Deserialize, Serialize};
+
+use crate::error::TilrError;
+use crate::layout::{Cell, Layout};
+use crate::output::StripSink;
+use crate::pixel::{convert, scale_alpha, Channel, RgbaBuffer};
+
+/// The shape of the cells of a [`Mosaic`](crate::Mosaic).
+///
+/// Sizes are given in base cells, each of which covers one block of
+/// [`Grid`](crate::Grid) pixels of the original image and one tile of the
+/// output. Cells along the edges of the mosaic are cut off by them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Shape {
+    /// Squares in aligned rows and columns.
+    #[default]
+    Square,
+    /// Cells one base cell in size, with every other row offset by half a
+    /// cell like the bricks of a wall.
+    Brick,
+    /// Pointy-topped hexagons one base cell wide and 4/3 tall, with every
+    /// other row offset by half a cell so that they interlock.
+    Hexagon,
+    /// Triangles one base cell wide and tall, alternately pointing up and
+    /// down.
+    Triangle,
+}
+
+/// How the cells of a [`Mosaic`](crate::Mosaic) are shaped, and what fills
+/// the gaps between them.
+///
+/// Each tile is resized to cover the bounding box of its cell, then clipped
+/// to the cell's shape.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tiling {
+    /// The shape of the cells.
+    pub shape: Shape,
+    /// The color of the grout between cells.
+    pub grout: Rgba<u8>,
+    /// The width of the grout between neighbouring cells, in pixels of the
+    /// output image. With `0.0`, the cells touch.
+    pub grout_width: f32,
+}
+
+impl Default for Tiling {
+    fn default() -> Self {
+        Self {
+            shape: Shape::default(),
+            grout: Rgba([0, 0, 0, 0]),
+            grout_width: 0.0,
+        }
+    }
+}
+
+impl Tiling {
+    /// Check whether cells are plain squares with nothing between them, so
+    /// tiles can be copied into the mosaic whole.
+    pub(crate) fn is_plain(&self) -> bool {
+        self.shape == Shape::Square && self.grout_width <= 0.0
+    }
+}
+
+impl Shape {
+    /// Get the offset between the column and row of a cell and the index of
+    /// the cell in its [`Layout`], which must not be negative.
+    fn shift(self) -> i64 {
+        match self {
+            Shape::Square => 0,
+            _ => 1,
+        }
+    }
+
+    /// Get the number of columns and rows of cell indices needed to cover
+    /// a mosaic of the given number of base cells.
+    pub(crate) fn index_size(self, columns: u32, rows: u32) -> (u32, u32) {
+        match self {
+            Shape::Square => (columns, rows),
+            Shape::Brick => (columns + 1, rows + 1),
+            Shape::Hexagon => (columns + 1, rows + 2),
+            Shape::Triangle => (2 * columns + 1, rows + 1),
+        }
+    }
+
+    /// Get the outline of the cell with the given index, in base cells.
+    pub(crate) fn polygon(self, x: u32, y: u32, span: u32) -> Polygon {
+        let (i, j) = (x as i64 - self.shift(), y as i64 - self.shift());
+        let (x, y, span) = (i as f64, j as f64, span as f64);
+        // every other row is offset by half a cell
+        let offset = if j.rem_euclid(2) == 1 { 0.5 } else { 0.0 };
+        match self {
+            Shape::Square => Polygon::new(&[[x, y], [x + span, y], [x + span, y + span], [x, y + span]]),
+            Shape::Brick => {
+                let x = x + offset;
+                Polygon::new(&[[x, y], [x + 1.0, y], [x + 1.0, y + 1.0], [x, y + 1.0]])
+            }
+            Shape::Hexagon => {
+                let (x, y) = (x + offset, y - 1.0 / 6.0);
+                Polygon::new(&[
+                    [x + 0.5, y],
+                    [x + 1.0, y + 1.0 / 3.0],
+                    [x + 1.0, y + 1.0],
+                    [x + 0.5, y + 4.0 / 3.0],
+                    [x, y + 1.0],
+                    [x, y + 1.0 / 3.0],
+                ])
+            }
+            Shape::Triangle => {
+                let x = x / 2.0;
+                if (i + j) % 2 == 0 {
+                    Polygon::new(&[[x + 0.5, y], [x + 1.0, y + 1.0], [x, y + 1.0]])
+                } else {
+                    Polygon::new(&[[x, y], [x + 1.0, y], [x + 0.5, y + 1.0]])
+                }
+            }
+        }
+    }
+
+    /// Get the indices of the cells that may contain the point (`u`, `v`),
+    /// given in base cells. One of them always does, if it exists.
+    pub(crate) fn candidates(self, u: f64, v: f64) -> impl Iterator<Item = (i64, i64)> {
+        let (column, row) = (u.floor() as i64, v.floor() as i64);
+        let in_row = move |j: i64| {
+            let offset = if j.rem_euclid(2) == 1 { 0.5 } else { 0.0 };
+            (u - offset).floor() as i64
+        };
+        let candidates: [Option<(i64, i64)>; 3] = match self {
+            Shape::Square => [Some((column, row)), None, None],
+            Shape::Brick => [Some((in_row(row), row)), None, None],
+            Shape::Hexagon => [row - 1, row, row + 1].map(|j| Some((in_row(j), j))),
+            Shape::Triangle => {
+                let k = (2.0 * u).floor() as i64;
+                [Some((k - 1, row)), Some((k, row)), None]
+            }
+        };
+        let shift = self.shift();
+        candidates.into_iter().flatten().map(move |(i, j)| (i + shift, j + shift))
+    }
+}
+
+/// A convex polygon, such as the outline of a cell.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Polygon {
+    /// The corners, in order around the polygon.
+    points: [[f64; 2]; 6],
+    /// The number of corners used.
+    len: usize,
+}
+
+impl Polygon {
+    /// Build a polygon from up to six corners.
+    fn new(corners: &[[f64; 2]]) -> Self {
+        let mut points = [[0.0; 2]; 6];
+        points[..corners.len()].copy_from_slice(corners);
+        Self { points, len: corners.len() }
+    }
+
+    /// Get the corners of the polygon.
+    fn corners(&self) -> &[[f64; 2]] {
+        &self.points[..self.len]
+    }
+
+    /// Scale the polygon, e.g. from base cells to pixels.
+    pub(crate) fn scaled(mut self, sx: f64, sy: f64) -> Self {
+        for p in &mut self.points[..self.len] {
+            *p = [p[0] * sx, p[1] * sy];
+        }
+        self
+    }
+
+    /// Get the bounding box of the polygon, as `[left, top, right, bottom]`.
+    pub(crate) fn bounds(&self) -> [f64; 4] {
+        let (mut lo, mut hi) = ([f64::INFINITY; 2], [f64::NEG_INFINITY; 2]);
+        for p in self.corners() {
+            for axis in 0..2 {
+                lo[axis] = lo[axis].min(p[axis]);
+                hi[axis] = hi[axis].max(p[axis]);
+            }
+        }
+        [lo[0], lo[1], hi[0], hi[1]]
+    }
+
+    /// Get the smallest box of whole pixels containing the polygon, as
+    /// `[left, top, right, bottom]` with the right and bottom exclusive.
+    pub(crate) fn pixel_bounds(&self) -> [i64; 4] {
+        let [left, top, right, bottom] = self.bounds();
+        [left.floor() as i64, top.floor() as i64, right.ceil() as i64, bottom.ceil() as i64]
+    }
+
+    /// Get the distance from a point to the nearest edge of the polygon,
+    /// which is positive inside it and negative outside.
+    pub(crate) fn depth(&self, x: f64, y: f64) -> f64 {
+        let corners = self.corners();
+        let area: f64 = corners
+            .iter()
+            .zip(corners.iter().cycle().skip(1))
+            .map(|(p, q)| p[0] * q[1] - q[0] * p[1])
+            .sum();
+        let sign = area.signum();
+        corners
+            .iter()
+            .zip(corners.iter().cycle().skip(1))
+            .map(|(p, q)| {
+                let (ex, ey) = (q[0] - p[0], q[1] - p[1]);
+                sign * (ex * (y - p[1]) - ey * (x - p[0])) / ex.hypot(ey)
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+/// Copy the pixels of an image under a polygon, given in pixels of the
+/// image, along with the rest of its bounding box.
+///
+/// Pixels outside the polygon keep only a token weight, so that they
+/// barely count toward averages, while grid regions entirely outside the
+/// polygon still get the color of the image around them rather than none.
+pub(crate) fn masked_crop<C: Channel>(img: &RgbaBuffer<C>, polygon: &Polygon) -> RgbaBuffer<C> {
+    let [left, top, right, bottom] = polygon.pixel_bounds();
+    let (left, top) = (left.max(0) as u32, top.max(0) as u32);
+    let right = (right.max(0) as u32).clamp(left + 1, img.width());
+    let bottom = (bottom.max(0) as u32).clamp(top + 1, img.height());
+    // the smallest weight that still counts
+    let token = C::from_unit(1.0 / 255.0);
+    RgbaBuffer::from_fn(right - left, bottom - top, |x, y| {
+        let (x, y) = (x + left, y + top);
+        let mut px = *img.get_pixel(x.min(img.width() - 1), y.min(img.height() - 1));
+        if polygon.depth(x as f64 + 0.5, y as f64 + 0.5) < 0.0 && px.channels()[3] > token {
+            px.channels_mut()[3] = token;
+        }
+        px
+    })
+}
+
+/// Render the cells of a layout, clipped to their shapes, into a sink one
+/// strip at a time.
+///
+/// `render` builds the image for a cell, given the cell, its tile and the
+/// size of its bounding box in the output. `alpha` gives the opacity of
+/// each pixel of the output, from `0.0` to `1.0`, from the original image.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn render_shaped_strips<C: Channel>(
+    layout: &Layout,
+    tiles: &[usize],
+    (width, height): (u32, u32),
+    (tile_width, tile_height): (u32, u32),
+    tiling: &Tiling,
+    render: impl Fn(&Cell, usize, u32, u32) -> Arc<RgbaBuffer<C>> + Sync,
+    alpha: impl Fn(u32, u32) -> f64 + Sync,
+    sink: &mut impl StripSink<C>,
+) -> Result<(), TilrError> {
+    let polygons: Vec<Polygon> = layout
+        .cells
+        .iter()
+        .map(|cell| layout.polygon(cell).scaled(tile_width as f64, tile_height as f64))
+        .collect();
+    let bounds: Vec<[i64; 4]> = polygons.iter().map(Polygon::pixel_bounds).collect();
+    let owners = layout.owners();
+    let half_grout = tiling.grout_width as f64 / 2.0;
+    let grout = convert::<C>(tiling.grout);
+
+    // the cell, if any, that the center of a pixel belongs to, and how far inside it is
+    let owner = |x: u32, y: u32| {
+        let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+        layout
+            .shape
+            .candidates(px / tile_width as f64, py / tile_height as f64)
+            .filter(|&(i, j)| i >= 0 && j >= 0 && i < layout.columns as i64 && j < layout.rows as i64)
+            .filter_map(|(i, j)| owners[j as usize * layout.columns as usize + i as usize])
+            .map(|cell| (cell, polygons[cell].depth(px, py)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    };
+
+    // cells are sorted by their top edge, and kept until their bottom edge
+    let mut rendered: Vec<Option<Arc<RgbaBuffer<C>>>> = vec![None; layout.cells.len()];
+    let mut next = 0;
+    for y0 in (0..height).step_by(tile_height as usize) {
+        let y1 = (y0 + tile_height).min(height);
+        let start = next;
+        while next < layout.cells.len() && bounds[next][1] < y1 as i64 {
+            next += 1;
+        }
+        let mut starting = Vec::new();
+        starting.par_extend((start..next).into_par_iter().map(|idx| {
+            let [left, top, right, bottom] = bounds[idx];
+            let size = ((right - left) as u32, (bottom - top) as u32);
+            (idx, render(&layout.cells[idx], tiles[idx], size.0, size.1))
+        }));
+        for (idx, img) in starting {
+            rendered[idx] = Some(img);
+        }
+
+        let mut strip = RgbaBuffer::new(width, y1 - y0);
+        strip
+            .par_chunks_mut(width as usize * 4)
+            .enumerate()
+            .for_each(|(row, pixels)| {
+                let y = y0 + row as u32;
+                for (x, px) in pixels.chunks_exact_mut(4).enumerate() {
+                    let x = x as u32;
+                    let mut color = match owner(x, y) {
+                        Some((cell, depth)) if depth >= half_grout => match &rendered[cell] {
+                            Some(img) => {
+                                let [left, top, ..] = bounds[cell];
+                                let tx = (x as i64 - left).clamp(0, img.width() as i64 - 1) as u32;
+                                let ty = (y as i64 - top).clamp(0, img.height() as i64 - 1) as u32;
+                                *img.get_pixel(tx, ty)
+                            }
+                            None => grout,
+                        },
+                        _ => grout,
+                    };
+                    scale_alpha::<C>(&mut color, alpha(x, y));
+                    px.copy_from_slice(color.channels());
+                }
+            });
+
+        for (idx, img) in rendered[..next].iter_mut().enumerate() {
+            if bounds[idx][3] <= y1 as i64 {
+                *img = None;
+            }
+        }
+        sink.write_strip(y0, &strip)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHAPES: [Shape; 4] = [Shape::Square, Shape::Brick, Shape::Hexagon, Shape::Triangle];
+
+    /// Get the index of every cell of the layout whose outline contains the
+    /// point, given in base cells.
+    fn containing(layout: &Layout, u: f64, v: f64) -> Vec<(i64, i64)> {
+        layout
+            .cells
+            .iter()
+            .filter(|cell| layout.polygon(cell).depth(u, v) > 0.0)
+            .map(|cell| (cell.x as i64, cell.y as i64))
+            .collect()
+    }
+
+    #[test]
+    fn cells_cover_the_canvas_once() {
+        for shape in SHAPES {
+            let layout = Layout::shaped(shape, 5, 4);
+            for k in 0..37 {
+                for l in 0..29 {
+                    let (u, v) = ((k as f64 + 0.31) * 5.0 / 37.0, (l as f64 + 0.43) * 4.0 / 29.0);
+                    let inside = containing(&layout, u, v);
+                    assert_eq!(inside.len(), 1, "{shape:?} at {u}, {v} is in {inside:?}");
+                    assert!(
+                        shape.candidates(u, v).any(|candidate| candidate == inside[0]),
+                        "{shape:?} at {u}, {v} should have {:?} as a candidate",
+                        inside[0]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn points_map_to_the_expected_cells() {
+        // a point and the bounds of the cell containing it, in base cells
+        let cases = [
+            (Shape::Square, (2.5, 1.5), [2.0, 1.0, 3.0, 2.0]),
+            (Shape::Brick, (0.25, 1.5), [-0.5, 1.0, 0.5, 2.0]),
+            (Shape::Brick, (0.75, 1.5), [0.5, 1.0, 1.5, 2.0]),
+            (Shape::Hexagon, (0.5, 0.5), [0.0, -1.0 / 6.0, 1.0, 7.0 / 6.0]),
+            (Shape::Hexagon, (1.0, 1.3), [0.5, 5.0 / 6.0, 1.5, 13.0 / 6.0]),
+            (Shape::Triangle, (0.5, 0.2), [0.0, 0.0, 1.0, 1.0]),
+            (Shape::Triangle, (0.1, 0.2), [-0.5, 0.0, 0.5, 1.0]),
+        ];
+        for (shape, (u, v), expected) in cases {
+            let owners: Vec<Polygon> = shape
+                .candidates(u, v)
+                .map(|(i, j)| shape.polygon(i as u32, j as u32, 1))
+                .filter(|polygon| polygon.depth(u, v) > 0.0)
+                .collect();
+            assert_eq!(owners.len(), 1, "{shape:?} at {u}, {v}");
+            let bounds = owners[0].bounds();
+            assert!(
+                bounds.iter().zip(expected).all(|(b, e)| (b - e).abs() < 1e-9),
+                "{shape:?} at {u}, {v} is in a cell with bounds {bounds:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn grout_fills_only_the_gaps_between_cells() {
+        let (width, height, tile_size) = (80, 60, 20);
+        let (white, blue) = (Rgba([255, 255, 255, 255]), Rgba([0, 0, 255, 255]));
+        for shape in SHAPES {
+            let layout = Layout::shaped(shape, width / tile_size, height / tile_size);
+            let tiling = Tiling { shape, grout: blue, grout_width: 4.0 };
+            let tiles: Vec<usize> = (0..layout.cells.len()).collect();
+            let mut mosaic = RgbaBuffer::<u8>::new(width, height);
+            render_shaped_strips(
+                &layout,
+                &tiles,
+                (width, height),
+                (tile_size, tile_size),
+                &tiling,
+                |_, _, w, h| Arc::new(RgbaBuffer::from_pixel(w, h, white)),
+                |_, _| 1.0,
+                &mut |y: u32, strip: &RgbaBuffer<u8>| {
+                    image::imageops::replace(&mut mosaic, strip, 0, y as i64);
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+            let (mut tile_pixels, mut grout_pixels) = (0, 0);
+            for (x, y, px) in mosaic.enumerate_pixels() {
+                let (px_x, px_y) = (x as f64 + 0.5, y as f64 + 0.5);
+                let depth = layout
+                    .cells
+                    .iter()
+                    .map(|cell| layout.polygon(cell).scaled(tile_size as f64, tile_size as f64).depth(px_x, px_y))
+                    .fold(f64::NEG_INFINITY, f64::max);
+                // pixels within half the grout width of an edge are grout
+                if depth > 2.0 + 1e-6 {
+                    assert_eq!(*px, white, "{shape:?} at {x}, {y}");
+                    tile_pixels += 1;
+                } else if depth < 2.0 - 1e-6 {
+                    assert_eq!(*px, blue, "{shape:?} at {x}, {y}");
+                    grout_pixels += 1;
+                }
+            }
+            assert!(tile_pixels > 0 && grout_pixels > 0, "{shape:?}");
+        }
+    }
+}
@@ -0,0 +1,284 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::color::{Color, ColorMetric};
+use crate::pixel::{alpha, Channel, RgbaBuffer};
+
+/// The layout of the regions whose average colors make up the signature
+/// of a [`Tile`](crate::Tile) or of a cell of a [`Mosaic`](crate::Mosaic).
+///
+/// A grid of `1×1` matches tiles on their average color alone. Larger
+/// grids keep edges and gradients inside a cell, since a tile that is half
+/// sky and half grass only matches a target cell that looks the same.
+///
+/// A grid must have at least one region across and down; a
+/// [`TileSet`](crate::TileSet) refuses to be built with an empty one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Grid {
+    /// The number of regions across.
+    pub columns: u32,
+    /// The number of regions down.
+    pub rows: u32,
+}
+
+impl Grid {
+    /// Create a grid with the given number of regions across and down.
+    ///
+    /// A grid with no regions is reported as [`TilrError::ZeroSize`] by
+    /// whatever it is used to build.
+    ///
+    /// [`TilrError::ZeroSize`]: crate::TilrError::ZeroSize
+    pub fn new(columns: u32, rows: u32) -> Self {
+        Self { columns, rows }
+    }
+
+    /// Get the number of regions in the grid.
+    pub fn regions(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+
+    /// Get the number of values in a signature using this grid.
+    pub(crate) fn dims(&self) -> usize {
+        self.regions() * 3
+    }
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self::new(1, 1)
+    }
+}
+
+/// How the colors of the pixels in each region of a [`Grid`] are combined
+/// into the single color the region has in a signature.
+///
+/// The same method is used for [`Tile`](crate::Tile)s and for the cells of
+/// a [`Mosaic`](crate::Mosaic), so that they stay comparable.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Averaging {
+    /// The mean color in the color space of the [`ColorMetric`]; for
+    /// [`ColorMetric::Rgb`], the mean of the sRGB-encoded values.
+    ///
+    /// Averaging encoded values makes regions of strong contrast darker
+    /// than they look from afar.
+    #[default]
+    Mean,
+    /// The mean color in linear light: colors are decoded from sRGB before
+    /// they are averaged, as the eye blends them when a tile is seen from
+    /// far enough away.
+    Linear,
+    /// The median of each channel in the color space of the metric, which
+    /// ignores small specks and highlights.
+    Median,
+    /// The dominant color: the center of the largest of this many clusters
+    /// of similar colors, found by k-means in the color space of the metric.
+    Dominant {
+        /// The number of clusters the colors are divided into.
+        clusters: u32,
+    },
+}
+
+/// The most pixels of a region that are clustered for
+/// [`Averaging::Dominant`]; larger regions are sampled evenly.
+const MAX_CLUSTER_SAMPLES: usize = 4096;
+
+/// The most rounds of k-means for [`Averaging::Dominant`].
+const MAX_CLUSTER_ROUNDS: usize = 16;
+
+impl Averaging {
+    /// Combine the given pixels into one color in the color space of the
+    /// given metric, weighting each pixel by its alpha.
+    fn combine<'a, C: Channel>(self, pixels: impl Iterator<Item = &'a C::Rgba>, metric: ColorMetric) -> Color {
+        match self {
+            Averaging::Mean => mean(pixels.map(|px| (C::to_space(px, metric), alpha::<C>(px)))),
+            Averaging::Linear => metric.linear_to_space(mean(pixels.map(|px| (C::to_linear(px), alpha::<C>(px))))),
+            Averaging::Median => {
+                let colors = weighted_colors::<C>(pixels, metric, usize::MAX);
+                std::array::from_fn(|c| median(colors.iter().map(|(color, w)| (color[c], *w))))
+            }
+            Averaging::Dominant { clusters } => {
+                let colors = weighted_colors::<C>(pixels, metric, MAX_CLUSTER_SAMPLES);
+                dominant(&colors, clusters.max(1) as usize, metric)
+            }
+        }
+    }
+}
+
+/// Compute the mean of the given colors, each with a weight.
+fn mean(colors: impl Iterator<Item = (Color, f64)>) -> Color {
+    let mut tot = [0f64; 3];
+    let mut weight = 0f64;
+    for (color, w) in colors {
+        for (t, c) in tot.iter_mut().zip(color) {
+            *t += w * c as f64;
+        }
+        weight += w;
+    }
+    tot.map(|t| (t / weight.max(f64::MIN_POSITIVE)) as f32)
+}
+
+/// Convert the visible pixels into the color space of the metric, with
+/// their alpha as their weight, keeping at most about `max` of them.
+fn weighted_colors<'a, C: Channel>(
+    pixels: impl Iterator<Item = &'a C::Rgba>,
+    metric: ColorMetric,
+    max: usize,
+) -> Vec<(Color, f64)> {
+    let pixels: Vec<_> = pixels.filter(|px| alpha::<C>(px) > 0.0).collect();
+    let step = pixels.len().div_ceil(max).max(1);
+    pixels
+        .into_iter()
+        .step_by(step)
+        .map(|px| (C::to_space(px, metric), alpha::<C>(px)))
+        .collect()
+}
+
+/// Compute the weighted median of the given values, or zero if there are
+/// none.
+fn median(values: impl Iterator<Item = (f32, f64)>) -> f32 {
+    let mut values: Vec<_> = values.collect();
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let half = values.iter().map(|(_, w)| w).sum::<f64>() / 2.0;
+    let mut acc = 0.0;
+    values
+        .iter()
+        .find(|(_, w)| {
+            acc += w;
+            acc >= half
+        })
+        .map_or(0.0, |(v, _)| *v)
+}
+
+/// Find the center of the heaviest of `k` clusters of the given colors by
+/// k-means, or black if there are no colors.
+fn dominant(colors: &[(Color, f64)], k: usize, metric: ColorMetric) -> Color {
+    let Some(&(first, _)) = colors.first() else {
+        return [0.0; 3];
+    };
+    let nearest = |centers: &[Color], color: &Color| {
+        (0..centers.len())
+            .map(|i| (i, metric.sq_dist(&centers[i], color)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or((0, 0.0), |best| best)
+    };
+
+    // start from colors as far apart as possible, so the result is stable
+    let mut centers = vec![first];
+    while centers.len() < k {
+        let (far, dist) = colors
+            .iter()
+            .map(|(color, _)| nearest(&centers, color).1)
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("colors should not be empty");
+        if dist <= 0.0 {
+            break;
+        }
+        centers.push(colors[far].0);
+    }
+
+    let mut weights = vec![0f64; centers.len()];
+    for _ in 0..MAX_CLUSTER_ROUNDS {
+        let mut sums = vec![([0f64; 3], 0f64); centers.len()];
+        for (color, w) in colors {
+            let (sum, weight) = &mut sums[nearest(&centers, color).0];
+            for (s, c) in sum.iter_mut().zip(color) {
+                *s += w * *c as f64;
+            }
+            *weight += w;
+        }
+
+        let mut moved = false;
+        for ((center, weight), (sum, total)) in centers.iter_mut().zip(&mut weights).zip(sums) {
+            *weight = total;
+            if total > 0.0 {
+                let next = sum.map(|s| (s / total) as f32);
+                moved |= next != *center;
+                *center = next;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    let heaviest = (0..centers.len()).max_by(|&a, &b| weights[a].total_cmp(&weights[b])).unwrap_or(0);
+    centers[heaviest]
+}
+
+/// A rectangular area of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Rect {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl Rect {
+    /// Get the area covering all of the given image.
+    pub(crate) fn of<C: Channel>(img: &RgbaBuffer<C>) -> Self {
+        Self { x: 0, y: 0, width: img.width(), height: img.height() }
+    }
+
+    /// Get the `i`th of `n` equal slices of the span `[start, start + len)`.
+    ///
+    /// Slices are never empty; if the span is shorter than `n`, neighbouring
+    /// slices share pixels.
+    fn slice(start: u32, len: u32, i: u32, n: u32) -> (u32, u32) {
+        let lo = (i * len / n).min(len.saturating_sub(1));
+        let hi = ((i + 1) * len / n).max(lo + 1);
+        (start + lo, start + hi)
+    }
+
+    /// Get the region at (`column`, `row`) when this area is divided by `grid`.
+    pub(crate) fn region(&self, grid: Grid, column: u32, row: u32) -> Self {
+        let (x0, x1) = Self::slice(self.x, self.width, column, grid.columns);
+        let (y0, y1) = Self::slice(self.y, self.height, row, grid.rows);
+        Self { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }
+    }
+}
+
+/// Compute the signature of an area of an image: the color, in the color
+/// space of the given [`ColorMetric`], of each region of `grid`, combined
+/// according to `averaging`.
+///
+/// Pixels are weighted by their alpha, so transparent pixels don't count
+/// toward the color of a region. The colors are flattened in row-major
+/// region order.
+pub(crate) fn signature<C: Channel>(
+    img: &RgbaBuffer<C>,
+    area: Rect,
+    grid: Grid,
+    metric: ColorMetric,
+    averaging: Averaging,
+) -> Vec<f32> {
+    let mut sig = Vec::with_capacity(grid.dims());
+    for row in 0..grid.rows {
+        for column in 0..grid.columns {
+            let region = area.region(grid, column, row);
+            let pixels = (region.y..region.y + region.height)
+                .flat_map(|y| (region.x..region.x + region.width).map(move |x| img.get_pixel(x, y)));
+            sig.extend(averaging.combine::<C>(pixels, metric));
+        }
+    }
+    sig
+}
+
+/// Check whether any pixel in an area of an image is not fully transparent.
+pub(crate) fn is_visible<C: Channel>(img: &RgbaBuffer<C>, area: Rect) -> bool {
+    (area.y..area.y + area.height)
+        .any(|y| (area.x..area.x + area.width).any(|x| alpha::<C>(img.get_pixel(x, y)) > 0.0))
+}
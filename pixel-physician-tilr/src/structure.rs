@@ -0,0 +1,99 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use image::Pixel;
+
+use crate::pixel::{alpha, Channel, RgbaBuffer};
+use crate::signature::{Grid, Rect};
+
+/// The number of directions that edges are sorted into.
+pub(crate) const ORIENTATIONS: usize = 8;
+
+/// The most lightness samples across and down an area that its edges are
+/// found from.
+const SAMPLES: u32 = 8;
+
+/// Matching [`Tile`](crate::Tile)s on the edges in them as well as on their
+/// colors, so that tiles whose content follows the edges of the original
+/// image are preferred.
+///
+/// The structure of a tile or cell is a histogram of the orientations of
+/// the edges in it, each weighted by its contrast. A sharp edge from black
+/// to white across the whole area counts as `100` in the direction it
+/// runs, which is about the size of a large color difference in CIELAB.
+///
+/// A cell only has structure if it covers more than one pixel of the
+/// original image, so this is most useful with a [`Grid`] larger than
+/// `1×1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Structure {
+    /// How much differences in structure count against differences in
+    /// color; the squared distances are summed with this weight.
+    pub weight: f32,
+}
+
+impl Structure {
+    /// Describe the structure of an area of an image, scaled so that the
+    /// squared distance between two descriptions carries the weight.
+    pub(crate) fn describe<C: Channel>(&self, img: &RgbaBuffer<C>, area: Rect) -> [f32; ORIENTATIONS] {
+        // Sample the lightness of the area on a small grid. Areas smaller
+        // than the grid are sampled pixel by pixel, so that a step between
+        // diagonal pixels still reads as a diagonal edge.
+        let (columns, rows) = (area.width.clamp(1, SAMPLES), area.height.clamp(1, SAMPLES));
+        let grid = Grid::new(columns, rows);
+        let lightness: Vec<Vec<f32>> = (0..rows)
+            .map(|row| {
+                (0..columns)
+                    .map(|column| {
+                        let region = area.region(grid, column, row);
+                        let (mut tot, mut weight) = (0f64, 0f64);
+                        for y in region.y..region.y + region.height {
+                            for x in region.x..region.x + region.width {
+                                let px = img.get_pixel(x, y);
+                                let [r, g, b] = [0, 1, 2].map(|c| px.channels()[c].to_unit());
+                                let alpha = alpha::<C>(px);
+                                tot += alpha * (0.2126 * r + 0.7152 * g + 0.0722 * b);
+                                weight += alpha;
+                            }
+                        }
+                        (tot / weight.max(f64::MIN_POSITIVE)) as f32
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Sum the contrast of the edges running in each direction, measuring
+        // gradients across the whole area so that the size of the sampling
+        // grid doesn't matter.
+        let (columns, rows) = (columns as usize, rows as usize);
+        let at = |x: usize, y: usize| lightness[y.min(rows - 1)][x.min(columns - 1)];
+        let mut histogram = [0f32; ORIENTATIONS];
+        for y in 0..rows {
+            for x in 0..columns {
+                let dx = (at(x + 1, y) - at(x.saturating_sub(1), y)) / 2.0 * columns as f32;
+                let dy = (at(x, y + 1) - at(x, y.saturating_sub(1))) / 2.0 * rows as f32;
+                let magnitude = dx.hypot(dy);
+                if magnitude > 0.0 {
+                    // edges run across the gradient, in either direction
+                    let angle = dy.atan2(dx).rem_euclid(std::f32::consts::PI);
+                    let bin = (angle / std::f32::consts::PI * ORIENTATIONS as f32) as usize % ORIENTATIONS;
+                    histogram[bin] += magnitude;
+                }
+            }
+        }
+        let scale = 100.0 / (columns * rows) as f32 * self.weight.max(0.0).sqrt();
+        histogram.map(|h| h * scale)
+    }
+}
@@ -13,48 +13,82 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use image::{DynamicImage, Rgb, RgbImage};
-use rayon::iter::{IntoParallelRefIterator, IndexedParallelIterator, ParallelIterator};
+use image::{DynamicImage, GenericImageView, Pixel, Rgb};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::color::ColorMetric;
+use crate::error::TilrError;
+use crate::index::KdTree;
+use crate::layout::Layout;
+use crate::lru::Lru;
+use crate::normalize::Normalize;
+use crate::pixel::{alpha, convert, Channel, RgbaBuffer};
+use crate::progress::{Monitor, Phase, Tracker};
+use crate::signature::{signature, Averaging, Grid, Rect};
+use crate::structure::{Structure, ORIENTATIONS};
+use crate::utils::load_image;
+use crate::variant::{Variant, Variants};
 
 /// Represents a single tile in a set; used to map
 /// between pixels in the original image and images
 /// in the [`TileSet`](super::TileSet).
+///
+/// The channels of the image are of type `C`; see [`Channel`].
 #[derive(Debug)]
-pub struct Tile {
+pub struct Tile<C: Channel = u8> {
     /// The underlying image to use for this Tile.
-    img: RgbImage,
-    /// The average pixel in the underlying image.
+    img: Arc<RgbaBuffer<C>>,
+    /// The average pixel of each [`Grid`] region of the underlying
+    /// image, in the color space of the [`ColorMetric`] of the
+    /// owning [`TileSet`].
     ///
     /// This is computed only once when the tile is
     /// first created to handle the case of very large
     /// images being used as tiles and making the mapping
     /// between image pixels and Tiles very slow.
-    avg: Rgb<u8>,
+    signature: Vec<f32>,
+    /// The file the underlying image was loaded from, if known.
+    path: Option<PathBuf>,
 }
 
-impl Tile {
-    /// Compute the Euclidean distance between the color
-    /// of the given pixel and the average pixel color
-    /// of this Tile.
-    pub fn sq_dist_to(&self, px: &Rgb<u8>) -> i32 {
-        // color values for the given px
-        let p_r = px.0[0] as i32;
-        let p_g = px.0[1] as i32;
-        let p_b = px.0[2] as i32;
+impl<C: Channel> Tile<C> {
+    /// Build a [`Tile`] from an RGBA image, averaging each region of
+    /// the grid in the color space of the given [`ColorMetric`].
+    ///
+    /// If the options have a background color, the image is composited
+    /// over it first. Otherwise, the image keeps its transparency.
+    pub(crate) fn new(mut img: RgbaBuffer<C>, options: &TileSetOptions) -> Self {
+        if let Some(background) = options.background {
+            composite_over(&mut img, background);
+        }
+        let signature = features(&img, Rect::of(&img), options);
+        Self::from_parts(img, signature, None)
+    }
+
+    /// Build a [`Tile`] from an image and a signature computed earlier.
+    pub(crate) fn from_parts(img: RgbaBuffer<C>, signature: Vec<f32>, path: Option<PathBuf>) -> Self {
+        Self {
+            img: Arc::new(img),
+            signature,
+            path,
+        }
+    }
 
-        // color values for the avg px color of the tile
-        let q_r = self.avg.0[0] as i32;
-        let q_g = self.avg.0[1] as i32;
-        let q_b = self.avg.0[2] as i32;
+    /// Get the signature of this Tile.
+    pub(crate) fn signature(&self) -> &[f32] {
+        &self.signature
+    }
 
-        // Euclidean distance
-        (p_r - q_r).pow(2) + (p_g - q_g).pow(2) + (p_b - q_b).pow(2)
+    /// Get the file the underlying image was loaded from, if known.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
     }
 
     /// Get the underlying image for this Tile.
-    pub fn img(&self) -> &RgbImage {
+    pub fn img(&self) -> &RgbaBuffer<C> {
         &self.img
     }
 
@@ -69,48 +103,171 @@ impl Tile {
     }
 }
 
-impl From<RgbImage> for Tile {
-    /// Build a [`Tile`] from an [`RgbImage`].
-    fn from(img: RgbImage) -> Self {
-        let avg_px_color = {
-            // get total for each color in the image
-            let mut tot_r = 0;
-            let mut tot_g = 0;
-            let mut tot_b = 0;
-            for px in img.pixels() {
-                tot_r += px.0[0] as usize;
-                tot_g += px.0[1] as usize;
-                tot_b += px.0[2] as usize;
-            }
-
-            // calculate the avg color for the image
-            // TODO: to we care about integer division here?
-            let num_px = img.pixels().len();
-            Rgb([
-                (tot_r / num_px) as u8,
-                (tot_g / num_px) as u8,
-                (tot_b / num_px) as u8,
-            ])
-        };
+/// Options controlling how a [`TileSet`] matches [`Tile`]s.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TileSetOptions {
+    /// The metric used to compare colors.
+    pub metric: ColorMetric,
+    /// How the colors of each region of a [`Tile`] or cell are combined
+    /// into its signature.
+    pub averaging: Averaging,
+    /// The grid of regions compared between each [`Tile`] and each
+    /// cell of the [`Mosaic`](crate::Mosaic).
+    pub grid: Grid,
+    /// The color to composite transparent [`Tile`]s over, or `None` to
+    /// keep their transparency in the mosaic.
+    ///
+    /// Either way, the colors of a tile are weighted by its alpha when
+    /// computing its signature.
+    pub background: Option<Rgb<u8>>,
+    /// How [`Tile`]s of differing sizes are brought to a common size.
+    pub normalize: Normalize,
+    /// Which rotations and mirror images of each [`Tile`] are also matched
+    /// against the cells, to get more out of small tile sets.
+    pub variants: Variants,
+    /// Also match [`Tile`]s on the edges in them, or `None` to match on
+    /// color alone.
+    pub structure: Option<Structure>,
+}
 
-        Self {
-            img,
-            avg: avg_px_color,
-        }
+impl TileSetOptions {
+    /// Get the number of components in each signature.
+    pub(crate) fn dims(&self) -> usize {
+        self.grid.dims() + self.structure.map_or(0, |_| ORIENTATIONS)
     }
 }
 
+/// The index, variant and size of a cached [`Tile`] image.
+type ImageKey = (usize, Variant, u32, u32);
+
+/// The most transformed or resized [`Tile`] images a [`TileSet`] keeps,
+/// unless [`TileSet::with_lazy_originals`] says otherwise.
+const CACHED_IMAGES: usize = 1024;
+
 /// A set of [`Tile`]s to use to build a [`Mosaic`](crate::Mosaic).
 ///
 /// This struct provides methods to map between the pixels in the original
 /// image to [`Tile`]s in order to build a [`Mosaic`](crate::Mosaic).
+///
+/// The channels of the tiles are of type `C`: 8-bit by default, or 16-bit
+/// or floating point to build mosaics without losing precision; see
+/// [`TileSet::from_images`].
 #[derive(Debug)]
-pub struct TileSet {
+pub struct TileSet<C: Channel = u8> {
     /// The [`Tile`]s in this set.
-    tiles: Vec<Tile>,
+    tiles: Vec<Tile<C>>,
+    /// The options used to build and match the [`Tile`]s.
+    options: TileSetOptions,
+    /// The variants offered for each [`Tile`].
+    ///
+    /// The candidates matched against the cells are every variant of every
+    /// tile: candidate `c` is variant `c % variants.len()` of tile
+    /// `c / variants.len()`.
+    variants: Vec<Variant>,
+    /// A spatial index over the signatures of the candidates,
+    /// used to find the closest one to a cell of the mosaic.
+    index: KdTree,
+    /// The most recently used copies of the [`Tile`] images transformed
+    /// into variants or resampled to other sizes, or decoded from their
+    /// files, keyed by tile index, variant and size.
+    images: Mutex<Lru<ImageKey, Arc<RgbaBuffer<C>>>>,
+    /// Whether images are decoded from the files of the [`Tile`]s; see
+    /// [`TileSet::with_lazy_originals`].
+    lazy: bool,
 }
 
 impl TileSet {
+    /// Build a tile set using the given images as 8-bit [`Tile`]s, matching
+    /// them against the mosaic according to the given options.
+    ///
+    /// Tiles that aren't all the same size are first brought to a common
+    /// size according to [`TileSetOptions::normalize`]. The signature of
+    /// each tile is then computed up front in the color space of the
+    /// metric, and indexed for fast nearest-tile lookups.
+    ///
+    /// # Errors
+    /// Fails if there are no images, if an image or the requested tile size
+    /// has no pixels, or if the signature grid has no regions.
+    pub fn new(imgs: Vec<DynamicImage>, options: TileSetOptions) -> Result<Self, TilrError> {
+        Self::from_images(imgs, options)
+    }
+}
+
+impl<C: Channel> TileSet<C> {
+    /// Build a tile set whose [`Tile`]s have channels of type `C`, as with
+    /// [`TileSet::new`].
+    ///
+    /// Use `u16` or `f32` tiles with 16-bit or HDR images to keep their
+    /// precision through to the mosaic.
+    ///
+    /// # Errors
+    /// Fails for the same reasons as [`TileSet::new`].
+    pub fn from_images(imgs: Vec<DynamicImage>, options: TileSetOptions) -> Result<Self, TilrError> {
+        let first = imgs.first().ok_or(TilrError::EmptyTileSet)?;
+        if options.grid.columns == 0 || options.grid.rows == 0 {
+            return Err(TilrError::ZeroSize("grid"));
+        }
+        if imgs.iter().any(|i| i.width() == 0 || i.height() == 0) {
+            return Err(TilrError::ZeroSize("tile image"));
+        }
+        let (width, height) = options.normalize.size.unwrap_or_else(|| first.dimensions());
+        if width == 0 || height == 0 {
+            return Err(TilrError::ZeroSize("tile size"));
+        }
+        let imgs: Vec<DynamicImage> = imgs
+            .into_par_iter()
+            .map(|i| options.normalize.apply(i, width, height))
+            .collect();
+
+        // build tiles from the resulting images
+        let tiles = imgs.into_par_iter().map(|i| Tile::new(C::from_dynamic(i), &options)).collect();
+        Self::from_tiles(tiles, options)
+    }
+
+    /// Build a tile set from [`Tile`]s whose signatures were already
+    /// computed with the given options.
+    ///
+    /// The signatures of any other variants are computed here, but their
+    /// images are only built once they are needed.
+    pub(crate) fn from_tiles(tiles: Vec<Tile<C>>, options: TileSetOptions) -> Result<Self, TilrError> {
+        if tiles.is_empty() {
+            return Err(TilrError::EmptyTileSet);
+        }
+        if options.grid.columns == 0 || options.grid.rows == 0 {
+            return Err(TilrError::ZeroSize("grid"));
+        }
+        let variants = options.variants.list();
+        let signatures: Vec<f32> = tiles
+            .par_iter()
+            .flat_map_iter(|tile| {
+                variants.iter().flat_map(|variant| match variant.apply(&tile.img) {
+                    Some(img) => features(&img, Rect::of(&img), &options),
+                    None => tile.signature.clone(),
+                })
+            })
+            .collect();
+        let index = KdTree::new(signatures, options.dims(), options.grid.dims(), options.metric);
+
+        Ok(Self {
+            tiles,
+            options,
+            variants,
+            index,
+            images: Mutex::new(Lru::new(CACHED_IMAGES)),
+            lazy: false,
+        })
+    }
+
+    /// Get the [`ColorMetric`] used to match cells to tiles.
+    pub fn metric(&self) -> ColorMetric {
+        self.options.metric
+    }
+
+    /// Get the [`Grid`] of regions compared between tiles and cells.
+    pub fn grid(&self) -> Grid {
+        self.options.grid
+    }
+
     /// Get the x length of the tiles.
     pub fn tile_x_len(&self) -> u32 {
         self.tiles[0].x_len()
@@ -121,49 +278,280 @@ impl TileSet {
         self.tiles[0].y_len()
     }
 
-    /// Create a mapping between pixels in the given image
-    /// and [`Tile`]s in the set.
-    pub fn map_to<'a>(&self, img: &'a RgbImage) -> HashMap<&'a Rgb<u8>, &Tile> {
-        let mut map = HashMap::new();
-        for px in img.pixels() {
-            if map.contains_key(px) {
-                continue; // don't duplicate closest tile calculations
-            }
-            map.insert(px, self.closest_tile(px));
+    /// Get the size tiles are rendered at unless another is asked for: the
+    /// size of the tiles, or with [`TileSet::with_lazy_originals`], the size
+    /// of the file of the first tile, so that the held thumbnails don't set
+    /// the resolution of the mosaic.
+    pub(crate) fn render_size(&self) -> (u32, u32) {
+        let original = self.tiles[0].path().filter(|_| self.lazy).and_then(|path| load_image(path).ok());
+        match original {
+            Some(img) if img.width() > 0 && img.height() > 0 => img.dimensions(),
+            _ => (self.tile_x_len(), self.tile_y_len()),
+        }
+    }
+
+    /// Get the number of cells across and down that the given image is
+    /// divided into, with each cell covering one block of [`Grid`] pixels.
+    pub fn cells_in(&self, img: &RgbaBuffer<C>) -> (u32, u32) {
+        let grid = self.grid();
+        (img.width() / grid.columns, img.height() / grid.rows)
+    }
+
+    /// Get the number of [`Tile`]s in the set.
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Check whether the set has no [`Tile`]s.
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    /// Get the [`Tile`]s in the set, in the order they were given.
+    pub fn tiles(&self) -> &[Tile<C>] {
+        &self.tiles
+    }
+
+    /// Render mosaics from the full-resolution files of the [`Tile`]s,
+    /// rather than from the images held in the set.
+    ///
+    /// The images held in the set are then only used for matching, so they
+    /// can be small thumbnails, e.g. from [`LoadOptions::thumbnail`](crate::LoadOptions::thumbnail)
+    /// or a [`TileCache`](crate::TileCache). Each file is decoded and
+    /// resized when a mosaic first uses it, and at most `capacity` of the
+    /// resulting images are kept, so memory grows with the tiles a mosaic
+    /// actually uses rather than with the size of the tile library.
+    ///
+    /// Tiles without a [`Tile::path`], or whose file can't be decoded any
+    /// more, are rendered from the images held in the set as usual.
+    pub fn with_lazy_originals(mut self, capacity: usize) -> Self {
+        self.images = Mutex::new(Lru::new(capacity));
+        self.lazy = true;
+        self
+    }
+
+    /// Remember the file each [`Tile`] was loaded from, in the order of
+    /// the tiles.
+    pub(crate) fn with_paths(mut self, paths: Vec<PathBuf>) -> Self {
+        for (tile, path) in self.tiles.iter_mut().zip(paths) {
+            tile.path = Some(path);
         }
+        self
+    }
+
+    /// Create a mapping between the cells of the given image and [`Tile`]s
+    /// in the set.
+    ///
+    /// Each cell is a block of [`Grid`] pixels, see [`TileSet::cells_in`].
+    /// The tiles are returned in row-major cell order. If the set offers
+    /// [`Variants`], each cell gets the tile whose variant matches best.
+    pub fn map_to(&self, img: &RgbaBuffer<C>) -> Vec<&Tile<C>> {
+        let (cells_x, cells_y) = self.cells_in(img);
+        let layout = Layout::uniform(cells_x, cells_y);
+        let monitor = Monitor::default();
+        self.cell_signatures(img, &layout, &monitor.phase(Phase::Signatures, layout.cells.len()))
+            .expect("nothing can cancel the signatures")
+            .par_iter()
+            .map(|sig| {
+                let (idx, _) = self.index.nearest(sig, self.metric()).expect("should have at least one tile");
+                &self.tiles[self.candidate(idx).0]
+            })
+            .collect()
+    }
 
-        map
+    /// Get the number of candidates matched against the cells: every
+    /// variant of every [`Tile`].
+    pub(crate) fn candidates(&self) -> usize {
+        self.tiles.len() * self.variants.len()
     }
 
-    /// Given a pixel, find the [`Tile`] in the set that most
-    /// closely matches it.
-    fn closest_tile(&self, px: &Rgb<u8>) -> &Tile {
-        #[derive(Debug)]
-        struct TileDist {
-            idx: usize,
-            dist: i32,
+    /// Get the index of the [`Tile`] and the variant of the candidate at the
+    /// given index.
+    pub(crate) fn candidate(&self, idx: usize) -> (usize, Variant) {
+        (idx / self.variants.len(), self.variants[idx % self.variants.len()])
+    }
+
+    /// Compute the signature of each cell of the given layout over the given
+    /// image, in the order of the layout's cells.
+    ///
+    /// Fails if the job tracked by `tracker` is cancelled.
+    pub(crate) fn cell_signatures(
+        &self,
+        img: &RgbaBuffer<C>,
+        layout: &Layout,
+        tracker: &Tracker<'_>,
+    ) -> Result<Vec<Vec<f32>>, TilrError> {
+        layout
+            .cells
+            .par_iter()
+            .map(|cell| {
+                tracker.check()?;
+                let (target, area) = layout.target(img, cell, self.grid());
+                let features = features(&target, area, &self.options);
+                tracker.advance();
+                Ok(features)
+            })
+            .collect()
+    }
+
+    /// Get the image of the candidate at the given index, resampled to the
+    /// given size if it isn't already that size.
+    pub(crate) fn tile_image(&self, idx: usize, width: u32, height: u32) -> Arc<RgbaBuffer<C>> {
+        let (tile, variant) = self.candidate(idx);
+        self.variant_image(tile, variant, width, height)
+    }
+
+    /// Get the image of a variant of the [`Tile`] at the given index,
+    /// resampled to the given size if it isn't already that size.
+    ///
+    /// Any variant can be built, even if the set doesn't match it against
+    /// cells. The most recently built images are cached, since the same
+    /// tile is often used many times in one mosaic.
+    ///
+    /// With [`TileSet::with_lazy_originals`], the image is decoded from the
+    /// tile's file instead, unless the file can't be decoded any more.
+    pub(crate) fn variant_image(
+        &self,
+        idx: usize,
+        variant: Variant,
+        width: u32,
+        height: u32,
+    ) -> Arc<RgbaBuffer<C>> {
+        let Some(path) = self.tiles[idx].path().filter(|_| self.lazy) else {
+            return self.held_image(idx, variant, width, height);
+        };
+        let key = (idx, variant, width, height);
+        if let Some(decoded) = self.images.lock().expect("tile image cache poisoned").get(&key) {
+            return decoded;
         }
-        let min_idx = self.tiles.par_iter().enumerate()
-            .map(|(i, t)| TileDist { idx: i, dist: t.sq_dist_to(px) })
-            .min_by_key(|d| d.dist)
-            .expect("should have at least one tile").idx;
-        &self.tiles[min_idx]
+        let decoded = match self.decode(path, variant, width, height) {
+            Some(decoded) => Arc::new(decoded),
+            None => self.held_image(idx, variant, width, height),
+        };
+        self.images.lock().expect("tile image cache poisoned").insert(key, Arc::clone(&decoded));
+        decoded
     }
-}
 
-impl From<Vec<DynamicImage>> for TileSet {
-    /// Build a tile set using the given images as [`Tile`]s.
-    fn from(imgs: Vec<DynamicImage>) -> Self {
-        let first_x = imgs[0].width();
-        let first_y = imgs[0].height();
+    /// Get the image of a variant of the [`Tile`] at the given index from
+    /// the image held in the set, as with [`TileSet::variant_image`].
+    fn held_image(&self, idx: usize, variant: Variant, width: u32, height: u32) -> Arc<RgbaBuffer<C>> {
+        let img = &self.tiles[idx].img;
+        if variant == Variant::Original && img.dimensions() == (width, height) {
+            return Arc::clone(img);
+        }
 
-        if !imgs.iter().all(|i| i.width() == first_x && i.height() == first_y) {
-            panic!("All images must be the same size!");
+        let key = (idx, variant, width, height);
+        if let Some(resized) = self.images.lock().expect("tile image cache poisoned").get(&key) {
+            return resized;
         }
+        let transformed = variant.apply(img);
+        let img = transformed.as_ref().unwrap_or(img);
+        let resized = match img.dimensions() == (width, height) {
+            true => Arc::new(img.clone()),
+            false => Arc::new(image::imageops::resize(img, width, height, self.options.normalize.filter)),
+        };
+        self.images.lock().expect("tile image cache poisoned").insert(key, Arc::clone(&resized));
+        resized
+    }
 
-        // build tiles from the resulting images
-        Self {
-            tiles: imgs.into_iter().map(|i| Tile::from(i.into_rgb8())).collect(),
+    /// Decode a variant of a [`Tile`] from its file at the given size,
+    /// prepared the same way as the image held in the set.
+    ///
+    /// Returns `None` if the file can't be decoded.
+    fn decode(&self, path: &Path, variant: Variant, width: u32, height: u32) -> Option<RgbaBuffer<C>> {
+        let img = load_image(path).ok().filter(|img| img.width() > 0 && img.height() > 0)?;
+        let mut img = C::from_dynamic(self.options.normalize.apply(img, width, height));
+        if let Some(background) = self.options.background {
+            composite_over(&mut img, background);
+        }
+        // quarter turns are stretched back to the size, as they were when matched
+        let img = variant.apply(&img).unwrap_or(img);
+        Some(match img.dimensions() == (width, height) {
+            true => img,
+            false => image::imageops::resize(&img, width, height, self.options.normalize.filter),
+        })
+    }
+
+    /// Get the signature of the candidate at the given index.
+    pub(crate) fn signature(&self, idx: usize) -> &[f32] {
+        self.index.point(idx)
+    }
+
+    /// Compute the squared distance between a signature and the signature
+    /// of the candidate at the given index.
+    pub(crate) fn sq_dist(&self, signature: &[f32], idx: usize) -> f32 {
+        self.index.sq_dist(signature, idx, self.metric())
+    }
+
+    /// Get the smallest and largest value of each color in the signatures
+    /// of the candidates.
+    pub(crate) fn signature_bounds(&self) -> Vec<(f32, f32)> {
+        let mut bounds = vec![(f32::INFINITY, f32::NEG_INFINITY); self.grid().dims()];
+        for idx in 0..self.candidates() {
+            for ((min, max), s) in bounds.iter_mut().zip(self.signature(idx)) {
+                *min = min.min(*s);
+                *max = max.max(*s);
+            }
         }
+        bounds
+    }
+
+    /// Get the average distance between the colors of each candidate and
+    /// its closest neighbour in the set, per [`Grid`] region.
+    pub(crate) fn palette_spacing(&self) -> f32 {
+        let candidates = self.candidates();
+        if candidates < 2 {
+            return 0.0;
+        }
+        let regions = self.grid().regions() as f32;
+        let total: f32 = (0..candidates)
+            .into_par_iter()
+            .map(|i| {
+                let (_, dist) = self.closest(self.signature(i), 1, |j| j != i)[0];
+                (dist / regions).sqrt()
+            })
+            .sum();
+        total / candidates as f32
+    }
+
+    /// Find up to `k` of the candidates closest to the given signature, out
+    /// of those for which `accept` returns `true`, closest first.
+    ///
+    /// Returns the index of each candidate and its squared distance.
+    pub(crate) fn closest(&self, signature: &[f32], k: usize, accept: impl Fn(usize) -> bool) -> Vec<(usize, f32)> {
+        self.index.nearest_k(signature, k, self.metric(), accept)
+    }
+}
+
+/// Compute the signature of an area of an image: the colors of its
+/// regions, followed by its structure if that is matched too.
+fn features<C: Channel>(img: &RgbaBuffer<C>, area: Rect, options: &TileSetOptions) -> Vec<f32> {
+    let mut features = signature(img, area, options.grid, options.metric, options.averaging);
+    if let Some(structure) = options.structure {
+        features.extend(structure.describe(img, area));
+    }
+    features
+}
+
+/// Composite an image over an opaque background color.
+fn composite_over<C: Channel>(img: &mut RgbaBuffer<C>, background: Rgb<u8>) {
+    let background = convert::<C>(background.to_rgba());
+    for px in img.pixels_mut() {
+        let alpha = alpha::<C>(px);
+        let channels = px.channels_mut();
+        for (c, bg) in channels.iter_mut().zip(background.channels()).take(3) {
+            *c = C::from_unit(alpha * c.to_unit() + (1.0 - alpha) * bg.to_unit());
+        }
+        channels[3] = C::from_unit(1.0);
+    }
+}
+
+impl TryFrom<Vec<DynamicImage>> for TileSet {
+    type Error = TilrError;
+
+    /// Build a tile set using the given images as [`Tile`]s, using
+    /// the default [`TileSetOptions`].
+    fn try_from(imgs: Vec<DynamicImage>) -> Result<Self, TilrError> {
+        Self::new(imgs, TileSetOptions::default())
     }
 }
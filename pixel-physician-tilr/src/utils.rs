@@ -13,34 +13,228 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::error::TilrError;
+use crate::normalize::apply_orientation;
+use crate::pixel::Channel;
+use crate::tiles::{TileSet, TileSetOptions};
+use glob::Pattern;
 use image::io::Reader as ImageReader;
 use image::DynamicImage;
-use std::error::Error;
-use std::fs;
-use std::path::Path;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Which files under a directory are loaded as tiles.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// Whether to descend into subdirectories.
+    pub recursive: bool,
+    /// Glob patterns, such as `*.jpg`, of which a file must match at least
+    /// one to be loaded; if empty, every file is loaded. Patterns are
+    /// matched against the path of the file relative to its root directory.
+    pub include: Vec<String>,
+    /// Glob patterns of files that are never loaded, even if they match an
+    /// `include` pattern.
+    pub exclude: Vec<String>,
+    /// Shrink each image to fit within this size as soon as it is decoded,
+    /// keeping its aspect ratio, so that a large library fits in memory.
+    /// Use [`TileSet::with_lazy_originals`] to still render mosaics from
+    /// the full-resolution files.
+    ///
+    /// A [`TileCache`](crate::TileCache) makes its own thumbnails, so it
+    /// ignores this.
+    pub thumbnail: Option<(u32, u32)>,
+}
+
+/// The tiles loaded by [`load_tiles_from`].
+#[derive(Debug, Default)]
+pub struct LoadedTiles {
+    /// The decoded images.
+    pub images: Vec<DynamicImage>,
+    /// The file each image was loaded from, in the same order.
+    pub paths: Vec<PathBuf>,
+    /// The files and directories that couldn't be read or decoded, and were
+    /// skipped.
+    pub skipped: Vec<TilrError>,
+}
+
+impl LoadedTiles {
+    /// Build a [`TileSet`] from the loaded images, whose [`Tile`](crate::Tile)s
+    /// remember the file they were loaded from.
+    ///
+    /// # Errors
+    /// Fails for the same reasons as [`TileSet::new`].
+    pub fn into_tile_set(self, options: TileSetOptions) -> Result<TileSet, TilrError> {
+        self.into_tile_set_of(options)
+    }
+
+    /// Build a [`TileSet`] whose [`Tile`](crate::Tile)s have channels of
+    /// type `C`, as with [`TileSet::from_images`].
+    ///
+    /// # Errors
+    /// Fails for the same reasons as [`TileSet::new`].
+    pub fn into_tile_set_of<C: Channel>(self, options: TileSetOptions) -> Result<TileSet<C>, TilrError> {
+        Ok(TileSet::from_images(self.images, options)?.with_paths(self.paths))
+    }
+}
 
 /// Load all images at the given `path` to use as tiles in the [`Mosaic`][crate::Mosaic]
-pub fn load_tiles(path: &Path) -> Result<Vec<DynamicImage>, Box<dyn Error>> {
-    if !path.is_dir() {
-        return Err(format!("Path must be a directory: {}", path.display()).into());
+///
+/// Images are rotated according to their EXIF orientation. They may be of
+/// any size; see [`Normalize`](crate::Normalize) for how they are brought to
+/// a common size.
+///
+/// Files that can't be read or decoded are skipped and listed in
+/// [`LoadedTiles::skipped`]; use [`load_tiles_from`] to load subdirectories
+/// too.
+///
+/// # Errors
+/// Fails if `path` isn't a readable directory.
+pub fn load_tiles(path: &Path) -> Result<LoadedTiles, TilrError> {
+    load_tiles_from([path], &LoadOptions::default())
+}
+
+/// Load the images under each of the given root directories to use as tiles
+/// in the [`Mosaic`][crate::Mosaic], decoding them in parallel.
+///
+/// Images are rotated according to their EXIF orientation, as with
+/// [`load_tiles`]. Files are loaded in order of their paths, and a file
+/// under more than one root, or reached by more than one path, is only
+/// loaded once.
+///
+/// # Errors
+/// Fails if a root isn't a readable directory or a pattern is invalid.
+/// Files, and subdirectories, that can't be read are skipped and listed
+/// in [`LoadedTiles::skipped`] instead.
+pub fn load_tiles_from<P: AsRef<Path>>(
+    roots: impl IntoIterator<Item = P>,
+    options: &LoadOptions,
+) -> Result<LoadedTiles, TilrError> {
+    let mut skipped = Vec::new();
+    let mut paths = Vec::new();
+    for root in roots {
+        paths.extend(tile_paths(root.as_ref(), options, &mut skipped)?);
+    }
+    paths.sort();
+    // the same file may be listed under differently spelled roots
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone())));
+
+    let results: Vec<_> = paths
+        .into_par_iter()
+        .map(|path| (load_image(&path).map(|img| shrink(img, options.thumbnail)), path))
+        .collect();
+    let mut loaded = LoadedTiles { skipped, ..Default::default() };
+    for (result, path) in results {
+        match result {
+            Ok(img) => {
+                loaded.images.push(img);
+                loaded.paths.push(path);
+            }
+            Err(e) => loaded.skipped.push(e),
+        }
+    }
+    Ok(loaded)
+}
+
+/// List the files under the given directory that may be tiles, in a stable
+/// order. Subdirectories that can't be read are added to `skipped`. Symbolic
+/// links to directories are not followed, so a link back to an ancestor
+/// can't make the walk loop forever.
+pub(crate) fn tile_paths(
+    root: &Path,
+    options: &LoadOptions,
+    skipped: &mut Vec<TilrError>,
+) -> Result<Vec<PathBuf>, TilrError> {
+    if !root.is_dir() {
+        return Err(TilrError::NotADirectory(root.to_path_buf()));
     }
+    let include = compile(&options.include)?;
+    let exclude = compile(&options.exclude)?;
+    let matches = |path: &Path| {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        (include.is_empty() || include.iter().any(|p| p.matches_path(relative)))
+            && !exclude.iter().any(|p| p.matches_path(relative))
+    };
 
-    let mut tiles = Vec::new();
+    let mut paths = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let io_err = |source| TilrError::Io { path: dir.clone(), source };
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // the root itself must be readable
+            Err(e) if dir == root => return Err(io_err(e)),
+            Err(e) => {
+                skipped.push(io_err(e));
+                continue;
+            }
+        };
 
-    for entry in fs::read_dir(path)? {
-        let entry = entry?;
-        let path = entry.path();
+        for entry in entries {
+            let (path, file_type) = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    skipped.push(io_err(e));
+                    continue;
+                }
+            };
 
-        if path.is_file() {
-            let tile = load(&path)?;
-            tiles.push(tile);
+            if path.is_file() && matches(&path) {
+                paths.push(path);
+            } else if options.recursive && file_type.is_dir() {
+                dirs.push(path);
+            }
         }
     }
 
-    Ok(tiles)
+    paths.sort();
+    Ok(paths)
+}
+
+/// Compile a list of glob patterns.
+fn compile(patterns: &[String]) -> Result<Vec<Pattern>, TilrError> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).map_err(|source| TilrError::Pattern { pattern: p.clone(), source }))
+        .collect()
+}
+
+/// Load a single image, such as a tile or the original image of a
+/// [`Mosaic`][crate::Mosaic], at its own bit depth.
+///
+/// The image is rotated according to its EXIF orientation, as with
+/// [`load_tiles`].
+///
+/// # Errors
+/// Fails if the file can't be read or decoded.
+pub fn load_image(path: &Path) -> Result<DynamicImage, TilrError> {
+    let io_err = |source| TilrError::Io { path: path.to_path_buf(), source };
+    let img = ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map_err(io_err)?
+        .decode()
+        .map_err(|source| TilrError::Decode { path: path.to_path_buf(), source })?;
+    Ok(match orientation(path) {
+        Some(orientation) => apply_orientation(img, orientation),
+        None => img,
+    })
+}
+
+/// Shrink an image to fit within the given size, keeping its aspect ratio,
+/// if it is any larger.
+fn shrink(img: DynamicImage, size: Option<(u32, u32)>) -> DynamicImage {
+    match size {
+        Some((width, height)) if img.width() > width || img.height() > height => img.thumbnail(width, height),
+        _ => img,
+    }
 }
 
-/// Load a single image to use as a tile in the [`Mosaic`][crate::Mosaic]
-fn load(tile: &Path) -> Result<DynamicImage, Box<dyn Error>> {
-    Ok(ImageReader::open(tile)?.decode()?)
+/// Read the EXIF orientation of an image file, if it has one.
+fn orientation(tile: &Path) -> Option<u32> {
+    let mut reader = BufReader::new(File::open(tile).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
 }
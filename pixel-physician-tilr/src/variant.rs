@@ -0,0 +1,94 @@
+// tilr - A program to build an image from a set of image 'tiles'.
+// Copyright (C) 2023  Charles German <5donuts@pm.me>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use image::imageops;
+use serde::{Deserialize, Serialize};
+
+use crate::pixel::{Channel, RgbaBuffer};
+
+/// Which rotations and mirror images of each [`Tile`](crate::Tile) a
+/// [`TileSet`](crate::TileSet) also offers as candidates for a cell.
+///
+/// Each variant is matched on its own signature, so a tile that is dark on
+/// the left can also fill cells that are dark on the right. Variant images
+/// are only built for the tiles actually placed in a mosaic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Variants {
+    /// Offer each tile rotated by 90°, 180° and 270°.
+    pub rotations: bool,
+    /// Offer each tile mirrored horizontally and vertically.
+    pub flips: bool,
+}
+
+impl Variants {
+    /// Get the variants offered for each tile, starting with the original.
+    pub(crate) fn list(&self) -> Vec<Variant> {
+        let mut list = vec![Variant::Original];
+        if self.rotations {
+            list.extend([Variant::Rotate90, Variant::Rotate180, Variant::Rotate270]);
+        }
+        if self.flips {
+            list.extend([Variant::FlipHorizontal, Variant::FlipVertical]);
+        }
+        list
+    }
+}
+
+/// A rotation or mirror image of a [`Tile`](crate::Tile).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Variant {
+    /// The tile as it is.
+    #[default]
+    Original,
+    /// The tile rotated clockwise by 90°.
+    Rotate90,
+    /// The tile rotated by 180°.
+    Rotate180,
+    /// The tile rotated clockwise by 270°.
+    Rotate270,
+    /// The tile mirrored left to right.
+    FlipHorizontal,
+    /// The tile mirrored top to bottom.
+    FlipVertical,
+}
+
+impl Variant {
+    /// Get the name of the variant, as it is written in a
+    /// [`PlacementMap`](crate::PlacementMap).
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Variant::Original => "original",
+            Variant::Rotate90 => "rotate90",
+            Variant::Rotate180 => "rotate180",
+            Variant::Rotate270 => "rotate270",
+            Variant::FlipHorizontal => "flip-horizontal",
+            Variant::FlipVertical => "flip-vertical",
+        }
+    }
+
+    /// Build this variant of an image. Returns `None` for
+    /// [`Variant::Original`], since the image can be used as it is.
+    pub(crate) fn apply<C: Channel>(self, img: &RgbaBuffer<C>) -> Option<RgbaBuffer<C>> {
+        match self {
+            Variant::Original => None,
+            Variant::Rotate90 => Some(imageops::rotate90(img)),
+            Variant::Rotate180 => Some(imageops::rotate180(img)),
+            Variant::Rotate270 => Some(imageops::rotate270(img)),
+            Variant::FlipHorizontal => Some(imageops::flip_horizontal(img)),
+            Variant::FlipVertical => Some(imageops::flip_vertical(img)),
+        }
+    }
+}
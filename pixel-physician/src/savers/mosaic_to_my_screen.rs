@@ -1,7 +1,12 @@
 use clap::Args;
 use humantime::Duration;
-use image::{DynamicImage, GenericImageView, RgbImage};
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbImage};
+use pixel_physician_tilr::{
+    CancelToken, Dither, Fit, MosaicBuilder, Selection, Sizing, TileSet, TileSetOptions, TilrError, Variants,
+};
 use rayon::iter::{IntoParallelIterator, ParallelIterator, IndexedParallelIterator};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use three_d::*;
 
 use crate::desktop_capture::{create_screenshot_taker, ScreenshotTaker};
@@ -14,6 +19,18 @@ pub struct MosaicToMyScreenStarter {
     /// Length of time to display a level.
     #[arg(short, long, default_value = "1s")]
     pub display_time: Duration,
+    /// Spread the color error of each cell to its neighbours when choosing
+    /// tiles, to break up bands of the same tile in gradients.
+    #[arg(long)]
+    pub dither: bool,
+    /// Also use each section rotated and mirrored, so the few sections of
+    /// the coarser levels cover more colors.
+    #[arg(long)]
+    pub variants: bool,
+    /// Give up on a capture whose levels take longer than this to compute,
+    /// and capture the screen again.
+    #[arg(long)]
+    pub max_render_time: Option<Duration>,
 }
 
 impl MosaicToMyScreenStarter {
@@ -111,29 +128,80 @@ impl MosaicToMyScreenState {
                 let sizes: Vec<Size> = (0..u32::MAX)
                     .map_while(|level| get_tile_size(&capture, level))
                     .collect();
-                let levels: Vec<RgbImage> = (0..sizes.len())
-                    .into_par_iter()
-                    .rev()
-                    .map(|idx| mosaify(&capture, sizes[idx]))
-                    .collect();
-
-                assert!(!levels.is_empty(), "no levels found");
+                let selection = Selection {
+                    dither: if self.args.dither { Dither::FloydSteinberg } else { Dither::None },
+                    ..Default::default()
+                };
+                let options = TileSetOptions {
+                    variants: Variants {
+                        rotations: self.args.variants,
+                        flips: self.args.variants,
+                    },
+                    ..Default::default()
+                };
+                assert!(!sizes.is_empty(), "no levels found");
+                let job = RenderJob::spawn(move |cancel| {
+                    (0..sizes.len())
+                        .into_par_iter()
+                        .rev()
+                        .map(|idx| mosaify(&capture, sizes[idx], options, selection, cancel))
+                        .collect()
+                });
 
                 ret = Some(FrameOutput {
                     swap_buffers: false,
                     ..Default::default()
                 });
-                if remaining_time <= 0.0 {
-                    MosaicState::DisplayingMosaic {
+                MosaicState::Rendering {
+                    remaining_time,
+                    elapsed_time: 0.0,
+                    job,
+                }
+            }
+            MosaicState::Rendering { mut remaining_time, mut elapsed_time, job } => {
+                remaining_time -= frame_input.elapsed_time;
+                elapsed_time += frame_input.elapsed_time;
+                ret = Some(FrameOutput {
+                    swap_buffers: false,
+                    ..Default::default()
+                });
+                let stale = self.args.max_render_time.is_some_and(|max| elapsed_time > max.as_millis() as f64);
+                match job.levels.try_recv() {
+                    Ok(Ok(levels)) if remaining_time <= 0.0 => MosaicState::DisplayingMosaic {
                         wrote_level: false,
                         remaining_time: self.args.display_time.as_millis() as f64,
                         levels,
                         level: 0,
-                    }
-                } else {
-                    MosaicState::WaitForTick {
+                    },
+                    Ok(Ok(levels)) => MosaicState::WaitForTick {
                         remaining_time,
                         levels,
+                    },
+                    Err(TryRecvError::Empty) if !stale => MosaicState::Rendering {
+                        remaining_time,
+                        elapsed_time,
+                        job,
+                    },
+                    // the job took too long, so dropping it cancels it and we start over
+                    Err(TryRecvError::Empty) | Ok(Err(TilrError::Cancelled)) => MosaicState::TransparentDraw {
+                        remaining_frames: 30,
+                    },
+                    // any other failure would only happen again, so give up
+                    Ok(Err(e)) => {
+                        eprintln!("failed to build the mosaic: {e}");
+                        ret = Some(FrameOutput {
+                            exit: true,
+                            ..Default::default()
+                        });
+                        MosaicState::TransparentDraw { remaining_frames: 0 }
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        eprintln!("failed to build the mosaic: the render thread stopped");
+                        ret = Some(FrameOutput {
+                            exit: true,
+                            ..Default::default()
+                        });
+                        MosaicState::TransparentDraw { remaining_frames: 0 }
                     }
                 }
             }
@@ -218,7 +286,7 @@ impl ScreenSaverState for MosaicToMyScreenState {
             .screen()
             .render(
                 &self.camera,
-                self.screen.into_iter(),
+                &self.screen,
                 &[&self.light],
             );
 
@@ -232,11 +300,18 @@ enum MosaicState {
     TransparentDraw {
         remaining_frames: usize,
     },
-    /// Screen is transparent and we are capturing the desktop, then computing
-    /// the mosaic levels.
+    /// Screen is transparent and we are capturing the desktop, then starting
+    /// to compute the mosaic levels.
     CaptureAndRender {
         remaining_time: f64,
     },
+    /// The mosaic levels are being computed in the background, which has
+    /// taken `elapsed_time` so far.
+    Rendering {
+        remaining_time: f64,
+        elapsed_time: f64,
+        job: RenderJob,
+    },
     /// The mosaic levels were computed before `remaining_time` elapsed.
     /// We need to wait for the remaining time.
     WaitForTick {
@@ -256,7 +331,43 @@ enum MosaicState {
     },
 }
 
-fn mosaify(source: &RgbImage, size: Size) -> RgbImage {
+/// The mosaic levels of a capture, computed on another thread.
+///
+/// Dropping the job cancels it, so a stale capture doesn't keep the CPU busy.
+#[derive(Debug)]
+struct RenderJob {
+    cancel: CancelToken,
+    levels: Receiver<Result<Vec<RgbImage>, TilrError>>,
+}
+
+impl RenderJob {
+    fn spawn(
+        render: impl FnOnce(&CancelToken) -> Result<Vec<RgbImage>, TilrError> + Send + 'static,
+    ) -> Self {
+        let cancel = CancelToken::new();
+        let (sender, levels) = mpsc::channel();
+        let token = cancel.clone();
+        std::thread::spawn(move || {
+            // the receiver is gone if the job was abandoned
+            let _ = sender.send(render(&token));
+        });
+        RenderJob { cancel, levels }
+    }
+}
+
+impl Drop for RenderJob {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+fn mosaify(
+    source: &RgbImage,
+    size: Size,
+    options: TileSetOptions,
+    selection: Selection,
+    cancel: &CancelToken,
+) -> Result<RgbImage, TilrError> {
     // Slice out tiles
     let sectioned = SectionedImage {
         texture: source,
@@ -266,30 +377,20 @@ fn mosaify(source: &RgbImage, size: Size) -> RgbImage {
         .map(|c| sectioned.get_section(c).into())
         .collect();
 
-    let (orig_width, orig_height) = (source.width(), source.height());
-
-    // scale down the image so that width * tile_width >= orig_width, etc.
-    let smaller_image = image::imageops::resize(
-        source,
-        (orig_width + size.width - 1) / size.width,
-        (orig_height + size.height - 1) / size.height,
-        image::imageops::FilterType::Nearest,
-    );
-
-    let mosaic = pixel_physician_tilr::Mosaic::new(
-        smaller_image,
-        tiles,
-        size.width,
-        size.height,
-    );
-
-    let mut mosaic = mosaic.into_image();
-
-    if mosaic.width() != orig_width || mosaic.height() != orig_height {
-        mosaic = mosaic.view(0, 0, orig_width, orig_height).to_image();
-    }
+    let tiles = TileSet::new(tiles, options)?;
+    let mosaic = MosaicBuilder::new(source.clone(), tiles)
+        .with_tile_size(size.width, size.height)
+        .with_sizing(Sizing::Output {
+            width: source.width(),
+            height: source.height(),
+        })
+        .with_fit(Fit::Stretch)
+        .with_filter(FilterType::Nearest)
+        .build()?
+        .with_selection(selection)
+        .with_cancel(cancel.clone());
 
-    mosaic
+    mosaic.into_image()
 }
 
 fn get_tile_size(source: &RgbImage, level: u32) -> Option<Size> {
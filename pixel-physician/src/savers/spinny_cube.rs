@@ -74,7 +74,7 @@ impl ScreenSaverState for SpinnyCubeState {
             .clear(ClearState::color_and_depth(0.8, 0.8, 0.8, 1.0, 1.0))
             .render(
                 &self.camera,
-                self.cube.into_iter(),
+                &self.cube,
                 &lights_vec(&self.lights),
             );
 
@@ -9,8 +9,8 @@ pub struct SectionedImage<'a> {
 
 impl<'a> SectionedImage<'a> {
     pub fn section_count(&self) -> Vector2<u32> {
-        let width = (self.texture.width() + self.section_size.width - 1) / self.section_size.width;
-        let height = (self.texture.height() + self.section_size.height - 1) / self.section_size.height;
+        let width = self.texture.width().div_ceil(self.section_size.width);
+        let height = self.texture.height().div_ceil(self.section_size.height);
         Vector2::new(width, height)
     }
 